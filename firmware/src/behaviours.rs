@@ -1,7 +1,10 @@
 use core::f32::consts::PI;
 
+use crate::color::{mix, sample, Interpolation};
+use crate::random::{hash, noise, unit};
 use crate::structs::{
-    AttackDecayEvent, ConstantEvent, HeartbeatEvent, MessageEvent,
+    AttackDecayEvent, ConstantEvent, GradientEvent, GrowEvent, HeartbeatEvent, MessageEvent,
+    NoiseEvent, RippleEvent, SparkleEvent,
 };
 use crate::{new_strips::STRIP_LENGTH, structs::Duration};
 use micromath::F32Ext;
@@ -9,31 +12,66 @@ use smart_leds_trait::RGB8;
 
 const INTENSITY_THESHOLD: f32 = 0.05;
 const TIME_THRESHOLD: f32 = 0.05;
+// the tail is down to exp(-4), about 2%, at its end
+const TAIL_DECAY: f32 = 4.0;
 
 pub fn paint_message_event(
     strip: &mut [RGB8; STRIP_LENGTH],
     event: &MessageEvent,
-    start_time_seconds: f32,
-    timer_seconds: f32,
+    elapsed_time_seconds: f32,
 ) -> () {
-    if timer_seconds - start_time_seconds < TIME_THRESHOLD {
+    if elapsed_time_seconds < TIME_THRESHOLD {
         return;
     }
 
-    let event_position = (timer_seconds - start_time_seconds) * event.pace;
+    let event_position = elapsed_time_seconds * event.pace;
+    let half_width = event.message_width as f32 / 2.0;
+    let (first, last) = message_window(
+        event,
+        (event_position - event.trailing_extent()).ceil() as i32,
+        (event_position + half_width).floor() as i32,
+    );
 
-    if event.start_idx < event.end_idx {
-        for idx in event.start_idx..event.end_idx {
-            let pixel_position = (idx - event.start_idx) as f32;
-            let intensity = get_message_pixel_intensity(pixel_position, event_position, event);
+    for pixel_position in first..=last {
+        let idx = message_pixel_idx(event, pixel_position);
+        let behind = event_position - pixel_position as f32;
+        if event.tail_length > 0 && behind > 0.0 {
+            let (color, intensity) = get_tail_pixel(behind, event);
+            strip[idx] = add_color(strip[idx], color, intensity);
+        } else {
+            let intensity = get_message_pixel_intensity(pixel_position as f32, event_position, event);
             strip[idx] = add_color(strip[idx], event.color, intensity);
         }
+    }
+}
+
+// Behind the head of a comet: the colour shifts towards the tail colour while it decays exponentially
+fn get_tail_pixel(behind: f32, event: &MessageEvent) -> (RGB8, f32) {
+    let fraction = behind / event.tail_length as f32;
+    if fraction > 1.0 {
+        return (event.color, 0.0);
+    }
+    let color = mix(event.color, event.tail_color, fraction, Interpolation::Rgb);
+    (color, (-TAIL_DECAY * fraction).exp())
+}
+
+// Narrows the positions lit by a message, counted in pixels from its start, down to those on its
+// path. Only these can be lit, so the rest of the path is never looked at. Empty when first > last.
+pub fn message_window(event: &MessageEvent, first_lit: i32, last_lit: i32) -> (i32, i32) {
+    // the start pixel itself is only part of the path when going up
+    let (first, last) = if event.start_idx < event.end_idx {
+        (0, (event.end_idx - event.start_idx) as i32 - 1)
     } else {
-        for idx in event.end_idx..event.start_idx {
-            let pixel_position = -(idx as f32 - event.start_idx as f32);
-            let intensity = get_message_pixel_intensity(pixel_position, event_position, event);
-            strip[idx] = add_color(strip[idx], event.color, intensity);
-        }
+        (1, (event.start_idx - event.end_idx) as i32)
+    };
+    (first.max(first_lit), last.min(last_lit))
+}
+
+pub fn message_pixel_idx(event: &MessageEvent, pixel_position: i32) -> usize {
+    if event.start_idx < event.end_idx {
+        event.start_idx + pixel_position as usize
+    } else {
+        event.start_idx - pixel_position as usize
     }
 }
 
@@ -58,10 +96,9 @@ fn get_message_pixel_intensity(
 pub fn paint_solid_pixel(
     strip: &mut [RGB8; STRIP_LENGTH],
     event: &ConstantEvent,
-    start_time_seconds: f32,
-    timer_seconds: f32,
+    elapsed_time_seconds: f32,
 ) -> () {
-    let elapsed = timer_seconds - start_time_seconds;
+    let elapsed = elapsed_time_seconds;
 
     // TODO - smoothing
     let intensity = if elapsed < event.fadein_duration as f32 {
@@ -73,16 +110,17 @@ pub fn paint_solid_pixel(
     };
     let intensity = 1.0;
 
-    strip[event.pixel_idx] = add_color(strip[event.pixel_idx], event.color, intensity)
+    for idx in event.pixels.indices() {
+        strip[idx] = add_color(strip[idx], event.color, intensity);
+    }
 }
 
 pub fn paint_heartbeat_pixel(
     strip: &mut [RGB8; STRIP_LENGTH],
     event: &HeartbeatEvent,
-    start_time_seconds: f32,
-    timer_seconds: f32,
+    elapsed_time_seconds: f32,
 ) -> () {
-    let elapsed = timer_seconds - start_time_seconds;
+    let elapsed = elapsed_time_seconds;
 
     if elapsed > event.duration {
         return;
@@ -111,7 +149,154 @@ pub fn paint_heartbeat_pixel(
         intensity = 1.0 - (1.0 - event.dimness) * (decay_time / event.second_pulse_decay).min(1.0);
     }
 
-    strip[event.pixel_idx] = add_color(strip[event.pixel_idx], event.color, intensity)
+    for idx in event.pixels.indices() {
+        strip[idx] = add_color(strip[idx], event.color, intensity);
+    }
+}
+
+pub fn paint_gradient_event(
+    strip: &mut [RGB8; STRIP_LENGTH],
+    event: &GradientEvent,
+    elapsed_time_seconds: f32,
+) {
+    let intensity = fade_envelope(
+        elapsed_time_seconds,
+        event.duration,
+        event.fadein_duration,
+        event.fadeout_duration,
+    );
+    if intensity <= 0.0 {
+        return;
+    }
+
+    let length = event.pixels.end as f32 - event.pixels.start as f32;
+    let offset = elapsed_time_seconds * event.scroll_speed;
+    for idx in event.pixels.indices() {
+        // scrolling wraps around, so the stops repeat along the range
+        let position = ((idx as f32 - event.pixels.start as f32 - offset) / length).rem_euclid(1.0);
+        strip[idx] = add_color(strip[idx], gradient_color(event, position), intensity);
+    }
+}
+
+// The colour a fraction of the way along the gradient, the first and last stops extend to the ends
+fn gradient_color(event: &GradientEvent, position: f32) -> RGB8 {
+    let stops = &event.stops[..event.stop_count as usize];
+    let position = position * 255.0;
+
+    let mut previous = stops[0];
+    for stop in stops {
+        if position <= stop.position as f32 {
+            let span = stop.position as f32 - previous.position as f32;
+            if span <= 0.0 {
+                return stop.color;
+            }
+            let t = (position - previous.position as f32) / span;
+            return mix(previous.color, stop.color, t, event.interpolation);
+        }
+        previous = *stop;
+    }
+    previous.color
+}
+
+// Linear fade in at the start and fade out at the end of the duration
+fn fade_envelope(elapsed: f32, duration: f32, fadein_duration: f32, fadeout_duration: f32) -> f32 {
+    let mut intensity: f32 = 1.0;
+    if fadein_duration > 0.0 {
+        intensity = intensity.min(elapsed / fadein_duration);
+    }
+    if fadeout_duration > 0.0 {
+        intensity = intensity.min((duration - elapsed) / fadeout_duration);
+    }
+    intensity.clamp(0.0, 1.0)
+}
+
+pub fn paint_sparkle_event(
+    strip: &mut [RGB8; STRIP_LENGTH],
+    event: &SparkleEvent,
+    elapsed_time_seconds: f32,
+) {
+    let cycle = event.attack + event.decay;
+    if elapsed_time_seconds > event.duration || cycle <= 0.0 {
+        return;
+    }
+
+    for idx in event.pixels.indices() {
+        // every pixel runs its own cycles, shifted by a random phase so they don't flash together
+        let time = elapsed_time_seconds + unit(hash(event.seed, idx as u32, 0)) * cycle;
+        let cycle_idx = (time / cycle) as u32;
+        let roll = hash(event.seed, idx as u32, cycle_idx + 1);
+        if unit(roll) >= event.density {
+            continue;
+        }
+
+        let time_in_cycle = time - cycle_idx as f32 * cycle;
+        let intensity = if time_in_cycle < event.attack {
+            time_in_cycle / event.attack
+        } else {
+            1.0 - (time_in_cycle - event.attack) / event.decay
+        };
+        let color = event.colors[(roll % event.color_count as u32) as usize];
+        strip[idx] = add_color(strip[idx], color, intensity.clamp(0.0, 1.0));
+    }
+}
+
+pub fn paint_ripple_event(
+    strip: &mut [RGB8; STRIP_LENGTH],
+    event: &RippleEvent,
+    elapsed_time_seconds: f32,
+) {
+    let half_width = event.width as f32 / 2.0;
+    let center = event.center_idx as i32;
+
+    for ring in 0..event.rings {
+        let ring_distance = (elapsed_time_seconds - ring as f32 * event.ring_interval) * event.speed;
+        if ring_distance < 0.0 || ring_distance - half_width > event.radius as f32 {
+            continue;
+        }
+        let fade = (-event.decay * ring_distance).exp();
+
+        let first = ((ring_distance - half_width).ceil() as i32).max(0);
+        let last = ((ring_distance + half_width).floor() as i32).min(event.radius as i32);
+        for distance in first..=last {
+            let intensity = fade * ring_intensity(distance as f32 - ring_distance, half_width);
+            // the centre pixel is on both sides, so it's only painted going up
+            let mut sides = [Some(center + distance), Some(center - distance)];
+            if distance == 0 {
+                sides[1] = None;
+            }
+            for idx in sides.into_iter().flatten() {
+                if idx >= 0 && (idx as usize) < STRIP_LENGTH {
+                    let idx = idx as usize;
+                    strip[idx] = add_color(strip[idx], event.color, intensity);
+                }
+            }
+        }
+    }
+}
+
+// The same cosine blob as a message's head, offset is the distance from the middle of the ring
+fn ring_intensity(offset: f32, half_width: f32) -> f32 {
+    if half_width <= 0.0 || offset.abs() > half_width {
+        return 0.0;
+    }
+    ((offset.abs() / half_width) * PI / 2.0).cos().clamp(0.0, 1.0)
+}
+
+pub fn paint_noise_event(
+    strip: &mut [RGB8; STRIP_LENGTH],
+    event: &NoiseEvent,
+    elapsed_time_seconds: f32,
+) {
+    if elapsed_time_seconds > event.duration {
+        return;
+    }
+
+    let colors = &event.colors[..event.color_count as usize];
+    let y = elapsed_time_seconds * event.speed;
+    for idx in event.pixels.indices() {
+        let value = noise(event.seed, idx as f32 * event.scale, y) * 0.5 + 0.5;
+        strip[idx] = add_color(strip[idx], sample(colors, value), event.brightness.min(1.0));
+    }
 }
 
 pub fn paint_attack_decay_event(
@@ -131,15 +316,60 @@ pub fn paint_attack_decay_event(
     let fill_idx = event.start_idx as f32 + level * (event.end_idx as f32 - event.start_idx as f32);
 
     for idx in event.start_idx..=event.end_idx {
-        let dist_to_fill = (fill_idx - idx as f32).abs();
-        let intensity = if dist_to_fill < event.smoothing_factor {
-            1.0 - (dist_to_fill / event.smoothing_factor)
-        } else if idx as f32 <= fill_idx {
+        let intensity = fill_intensity(idx as f32, fill_idx, event.smoothing_factor);
+        strip[idx] = add_color(strip[idx], event.color, intensity);
+    }
+}
+
+// Lit up to the fill position, with the edge smoothed over smoothing pixels either side of it
+fn fill_intensity(position: f32, fill: f32, smoothing: f32) -> f32 {
+    let dist_to_fill = (fill - position).abs();
+    if dist_to_fill < smoothing {
+        1.0 - (dist_to_fill / smoothing)
+    } else if position <= fill {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+pub fn paint_grow_event(
+    strip: &mut [RGB8; STRIP_LENGTH],
+    event: &GrowEvent,
+    elapsed_time_seconds: f32,
+) {
+    let grow_duration = event.reach / event.speed;
+    let withering = elapsed_time_seconds - grow_duration - event.hold;
+    let front = if elapsed_time_seconds < grow_duration {
+        elapsed_time_seconds * event.speed
+    } else if withering <= 0.0 {
+        event.reach
+    } else if withering < event.wither {
+        event.reach * (1.0 - withering / event.wither)
+    } else {
+        return;
+    };
+
+    // how far the front has come along this branch
+    let fill = front - event.delay;
+    if fill <= -event.smoothing {
+        return;
+    }
+
+    let (start, end) = (event.start_idx as i32, event.end_idx as i32);
+    let step = if end >= start { 1 } else { -1 };
+    for position in 0..=(end - start).abs() {
+        let idx = (start + position * step) as usize;
+        if idx >= STRIP_LENGTH {
+            continue;
+        }
+        // only the ramp ahead of the front, what has grown stays fully lit
+        let position = position as f32;
+        let intensity = if position <= fill {
             1.0
         } else {
-            0.0
+            fill_intensity(position, fill, event.smoothing)
         };
-
         strip[idx] = add_color(strip[idx], event.color, intensity);
     }
 }
@@ -187,4 +417,128 @@ mod tests {
 
         assert_eq!(result.len(), 100);
     }
+
+    #[test]
+    fn grow_reaches_branches_late_and_withers_from_the_tips() {
+        let green = RGB8 { r: 0, g: 200, b: 0 };
+        let event = |start_idx, end_idx, delay| GrowEvent {
+            color: green,
+            strip_idx: 0,
+            start_idx,
+            end_idx,
+            delay,
+            reach: 60.0,
+            speed: 10.0,
+            smoothing: 2.0,
+            hold: 1.0,
+            wither: 6.0,
+        };
+        // the origin runs down from pixel 40 to 0, a branch off its end runs from 100 to 120
+        let (origin, branch) = (event(40, 0, 0.0), event(100, 120, 40.0));
+        let paint = |time| {
+            let mut strip = [RGB8 { r: 0, g: 0, b: 0 }; STRIP_LENGTH];
+            paint_grow_event(&mut strip, &origin, time);
+            paint_grow_event(&mut strip, &branch, time);
+            strip
+        };
+
+        let strip = paint(2.0);
+        assert_eq!(strip[21], green);
+        assert_eq!(strip[20], green);
+        // the soft edge ahead of the front
+        assert_eq!(strip[19], RGB8 { r: 0, g: 100, b: 0 });
+        assert_eq!(strip[18], RGB8 { r: 0, g: 0, b: 0 });
+        assert_eq!(strip[100], RGB8 { r: 0, g: 0, b: 0 });
+
+        let strip = paint(5.0);
+        assert_eq!(strip[0], green);
+        assert_eq!(strip[110], green);
+        assert_eq!(strip[112], RGB8 { r: 0, g: 0, b: 0 });
+
+        // withering back, the branch goes first
+        let strip = paint(6.0 + 1.0 + 3.0);
+        assert_eq!(strip[100], RGB8 { r: 0, g: 0, b: 0 });
+        assert_eq!(strip[10], green);
+        assert!(paint(13.5).iter().all(|pixel| *pixel == RGB8 { r: 0, g: 0, b: 0 }));
+    }
+
+    #[test]
+    fn comet_tail_trails_behind_the_head() {
+        let event = MessageEvent {
+            color: RGB8 { r: 200, g: 200, b: 0 },
+            message_width: 4,
+            pace: 10.0,
+            strip_idx: 0,
+            start_idx: 0,
+            end_idx: 150,
+            tail_length: 20,
+            tail_color: RGB8 { r: 0, g: 0, b: 200 },
+            tail_follows_color: false,
+        };
+        let mut strip = [RGB8 { r: 0, g: 0, b: 0 }; STRIP_LENGTH];
+        // the head is at pixel 50
+        paint_message_event(&mut strip, &event, 5.0);
+
+        assert_eq!(strip[50], event.color);
+        assert_eq!(strip[53], RGB8 { r: 0, g: 0, b: 0 });
+        assert!(strip[45].r > strip[40].r && strip[40].r > strip[35].r);
+        assert!(strip[35].b > 0 && strip[31].r < strip[49].r);
+        assert_eq!(strip[29], RGB8 { r: 0, g: 0, b: 0 });
+    }
+
+    #[test]
+    fn ripple_spreads_both_ways_and_stops_at_the_ends() {
+        let event = RippleEvent {
+            color: RGB8 { r: 0, g: 100, b: 200 },
+            center_idx: 5,
+            speed: 10.0,
+            width: 4,
+            radius: 50,
+            decay: 0.0,
+            rings: 2,
+            ring_interval: 1.0,
+            strip_idx: 0,
+        };
+        let mut strip = [RGB8 { r: 0, g: 0, b: 0 }; STRIP_LENGTH];
+        // the first ring is 15 pixels out, the second just left the centre
+        paint_ripple_event(&mut strip, &event, 1.5);
+
+        assert_eq!(strip[20], event.color);
+        assert_eq!(strip[10], event.color);
+        assert_eq!(strip[0], event.color);
+        assert_eq!(strip[15], RGB8 { r: 0, g: 0, b: 0 });
+        assert_eq!(strip[23], RGB8 { r: 0, g: 0, b: 0 });
+    }
+
+    #[test]
+    fn message_window_covers_every_lit_pixel() {
+        for (start_idx, end_idx) in [(10, 150), (150, 10)] {
+            let event = MessageEvent {
+                color: RGB8 { r: 255, g: 255, b: 255 },
+                message_width: 8,
+                pace: 17.0,
+                strip_idx: 0,
+                start_idx,
+                end_idx,
+                tail_length: 0,
+                tail_color: RGB8 { r: 0, g: 0, b: 0 },
+                tail_follows_color: false,
+            };
+            for step in 0..200 {
+                let elapsed = 0.05 + step as f32 * 0.043;
+                let mut strip = [RGB8 { r: 0, g: 0, b: 0 }; STRIP_LENGTH];
+                paint_message_event(&mut strip, &event, elapsed);
+
+                // every pixel of the path, the way it was painted before the window
+                let (low, high) = (start_idx.min(end_idx), start_idx.max(end_idx));
+                for (idx, pixel) in strip.iter().enumerate().take(high).skip(low) {
+                    let pixel_position = (idx as f32 - start_idx as f32).abs();
+                    let intensity =
+                        get_message_pixel_intensity(pixel_position, elapsed * event.pace, &event);
+                    let expected = add_color(RGB8 { r: 0, g: 0, b: 0 }, event.color, intensity);
+                    assert_eq!(*pixel, expected, "pixel {} at {}s", idx, elapsed);
+                }
+            }
+        }
+    }
 }
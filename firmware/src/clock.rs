@@ -0,0 +1,189 @@
+// Virtual clock which drives all the animations, so that the exhibit can be paused,
+// slowed down or stepped frame by frame without touching the event start times. The virtual time
+// is worked out from the real time since the last speed or tempo change rather than summed frame by
+// frame, f32 deltas of a frame would drift and after a few hours stop moving the clock at all.
+pub const DEFAULT_STEP_SECONDS: f32 = 1.0 / 60.0;
+// at the default tempo a beat lasts exactly one second
+pub const DEFAULT_BPM: f32 = 60.0;
+
+// Which clock an event measures its start time, durations and paces in
+#[derive(Copy, Clone, PartialEq)]
+pub enum TimeBase {
+    Seconds,
+    Beats,
+}
+
+#[derive(Copy, Clone)]
+pub struct Timestamp {
+    pub seconds: f32,
+    pub beats: f32,
+}
+
+impl Timestamp {
+    pub fn in_base(&self, time_base: TimeBase) -> f32 {
+        match time_base {
+            TimeBase::Seconds => self.seconds,
+            TimeBase::Beats => self.beats,
+        }
+    }
+}
+
+pub struct VirtualClock {
+    paused: bool,
+    speed: f32,
+    step_seconds: f32,
+    pending_steps: u32,
+    last_real_seconds: Option<f32>,
+    virtual_seconds: f32,
+    bpm: f32,
+    beats: f32,
+    // the clock at the last speed or tempo change, which the time since is counted from so that
+    // such a change never makes running events jump
+    base_real_seconds: Option<f32>,
+    base_virtual_seconds: f32,
+    base_beats: f32,
+}
+
+impl VirtualClock {
+    pub const fn new() -> Self {
+        VirtualClock {
+            paused: false,
+            speed: 1.0,
+            step_seconds: DEFAULT_STEP_SECONDS,
+            pending_steps: 0,
+            last_real_seconds: None,
+            virtual_seconds: 0.0,
+            bpm: DEFAULT_BPM,
+            beats: 0.0,
+            base_real_seconds: None,
+            base_virtual_seconds: 0.0,
+            base_beats: 0.0,
+        }
+    }
+
+    // Advance the clock to the given real time and return the new virtual time
+    pub fn tick(&mut self, real_seconds: f32) -> Timestamp {
+        self.last_real_seconds = Some(real_seconds);
+        let base_real_seconds = *self.base_real_seconds.get_or_insert(real_seconds);
+
+        if !self.paused {
+            self.virtual_seconds =
+                self.base_virtual_seconds + (real_seconds - base_real_seconds).max(0.0) * self.speed;
+        } else if self.pending_steps > 0 {
+            self.virtual_seconds += self.step_seconds;
+            self.pending_steps -= 1;
+        }
+        self.beats = self.base_beats + (self.virtual_seconds - self.base_virtual_seconds) * self.bpm / 60.0;
+
+        self.now()
+    }
+
+    pub fn now(&self) -> Timestamp {
+        Timestamp {
+            seconds: self.virtual_seconds,
+            beats: self.beats,
+        }
+    }
+
+    pub fn bpm(&self) -> f32 {
+        self.bpm
+    }
+
+    pub fn set_bpm(&mut self, bpm: f32) {
+        self.rebase();
+        self.bpm = bpm.max(0.0);
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn speed(&self) -> f32 {
+        self.speed
+    }
+
+    pub fn pause(&mut self) {
+        self.paused = true;
+        self.pending_steps = 0;
+    }
+
+    pub fn resume(&mut self) {
+        self.rebase();
+        self.paused = false;
+        self.pending_steps = 0;
+    }
+
+    pub fn set_speed(&mut self, speed: f32) {
+        self.rebase();
+        self.speed = speed.max(0.0);
+    }
+
+    // From the last tick on the virtual time and the beats run from where they are now
+    fn rebase(&mut self) {
+        self.base_real_seconds = self.last_real_seconds;
+        self.base_virtual_seconds = self.virtual_seconds;
+        self.base_beats = self.beats;
+    }
+
+    // Pauses the clock and advances it by `frames` frames of `step_seconds`, one per tick
+    pub fn step(&mut self, frames: u32, step_seconds: f32) {
+        self.paused = true;
+        self.step_seconds = step_seconds.max(0.0);
+        self.pending_steps += frames;
+    }
+}
+
+impl Default for VirtualClock {
+    fn default() -> Self {
+        VirtualClock::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pause_speed_and_step() {
+        let mut clock = VirtualClock::new();
+        assert_eq!(clock.tick(5.0).seconds, 0.0);
+        assert_eq!(clock.tick(6.0).seconds, 1.0);
+
+        clock.set_speed(0.5);
+        assert_eq!(clock.tick(8.0).seconds, 2.0);
+
+        clock.pause();
+        assert_eq!(clock.tick(20.0).seconds, 2.0);
+
+        clock.step(2, 0.25);
+        assert_eq!(clock.tick(21.0).seconds, 2.25);
+        assert_eq!(clock.tick(22.0).seconds, 2.5);
+        assert_eq!(clock.tick(23.0).seconds, 2.5);
+
+        clock.resume();
+        assert_eq!(clock.tick(25.0).seconds, 3.5);
+    }
+
+    #[test]
+    fn tempo_changes_keep_beats_continuous() {
+        let mut clock = VirtualClock::new();
+        clock.tick(0.0);
+        assert_eq!(clock.tick(2.0).beats, 2.0);
+
+        clock.set_bpm(120.0);
+        assert_eq!(clock.tick(3.0).beats, 4.0);
+        assert_eq!(clock.now().seconds, 3.0);
+    }
+
+    #[test]
+    fn no_drift_after_hours() {
+        let mut clock = VirtualClock::new();
+        clock.tick(0.0);
+        for frame in 0..600 {
+            let real_seconds = 36000.0 + frame as f32 / 60.0;
+            let now = clock.tick(real_seconds);
+            assert!((now.seconds - real_seconds).abs() < 0.01);
+            assert!((now.beats - real_seconds).abs() < 0.01);
+        }
+    }
+}
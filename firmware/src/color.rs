@@ -0,0 +1,269 @@
+#[cfg(not(test))]
+use micromath::F32Ext;
+use smart_leds_trait::RGB8;
+
+// How colours in between two given ones are worked out
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Interpolation {
+    // straight line between the channels, cheap but muddy in the middle
+    Rgb,
+    // around the colour wheel the short way, keeps the colours saturated
+    Hsv,
+    // perceptually even steps in brightness and hue
+    Oklab,
+}
+
+impl Interpolation {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "rgb" => Some(Interpolation::Rgb),
+            "hsv" => Some(Interpolation::Hsv),
+            "oklab" => Some(Interpolation::Oklab),
+            _ => None,
+        }
+    }
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+fn to_channel(value: f32) -> u8 {
+    (value * 255.0).round().clamp(0.0, 255.0) as u8
+}
+
+// The colour a fraction t of the way from a to b
+pub fn mix(a: RGB8, b: RGB8, t: f32, interpolation: Interpolation) -> RGB8 {
+    if t <= 0.0 {
+        return a;
+    }
+    if t >= 1.0 {
+        return b;
+    }
+
+    match interpolation {
+        Interpolation::Rgb => RGB8 {
+            r: lerp(a.r as f32, b.r as f32, t).round() as u8,
+            g: lerp(a.g as f32, b.g as f32, t).round() as u8,
+            b: lerp(a.b as f32, b.b as f32, t).round() as u8,
+        },
+        Interpolation::Hsv => {
+            let (a, b) = (rgb_to_hsv(a), rgb_to_hsv(b));
+            let mut hue_step = b.0 - a.0;
+            if hue_step > 0.5 {
+                hue_step -= 1.0;
+            } else if hue_step < -0.5 {
+                hue_step += 1.0;
+            }
+            hsv_to_rgb(a.0 + hue_step * t, lerp(a.1, b.1, t), lerp(a.2, b.2, t))
+        }
+        Interpolation::Oklab => {
+            let (a, b) = (rgb_to_oklab(a), rgb_to_oklab(b));
+            oklab_to_rgb([lerp(a[0], b[0], t), lerp(a[1], b[1], t), lerp(a[2], b[2], t)])
+        }
+    }
+}
+
+// A colour from a list spread evenly from position 0 to 1, blending between neighbours
+pub fn sample(colors: &[RGB8], position: f32) -> RGB8 {
+    if colors.len() < 2 {
+        return colors.first().copied().unwrap_or(RGB8 { r: 0, g: 0, b: 0 });
+    }
+    let scaled = position.clamp(0.0, 1.0) * (colors.len() - 1) as f32;
+    let idx = (scaled as usize).min(colors.len() - 2);
+    mix(colors[idx], colors[idx + 1], scaled - idx as f32, Interpolation::Rgb)
+}
+
+// Turns the colour a fraction of the way around the colour wheel, keeping saturation and value
+pub fn rotate_hue(color: RGB8, turns: f32) -> RGB8 {
+    let (hue, saturation, value) = rgb_to_hsv(color);
+    hsv_to_rgb(hue + turns, saturation, value)
+}
+
+// "#rrggbb", "rrggbb" or the short "#rgb"
+pub fn parse_hex(hex: &str) -> Option<RGB8> {
+    let hex = hex.strip_prefix('#').unwrap_or(hex);
+    let digit = |idx: usize| hex.get(idx..idx + 1).and_then(|d| u8::from_str_radix(d, 16).ok());
+    match hex.len() {
+        6 => Some(RGB8 {
+            r: digit(0)? << 4 | digit(1)?,
+            g: digit(2)? << 4 | digit(3)?,
+            b: digit(4)? << 4 | digit(5)?,
+        }),
+        3 => Some(RGB8 {
+            r: digit(0)? * 17,
+            g: digit(1)? * 17,
+            b: digit(2)? * 17,
+        }),
+        _ => None,
+    }
+}
+
+// The colour of a black body at the given temperature, between 1000K (candle) and 40000K (blue sky).
+// Tanner Helland's curve fit, which is within a few percent of the measured colours.
+pub fn kelvin_to_rgb(kelvin: f32) -> RGB8 {
+    let t = kelvin.clamp(1000.0, 40000.0) / 100.0;
+    let clamp = |channel: f32| channel.round().clamp(0.0, 255.0) as u8;
+
+    let r = if t <= 66.0 { 255.0 } else { 329.698_73 * (t - 60.0).powf(-0.133_204_76) };
+    let g = if t <= 66.0 {
+        99.470_8 * t.ln() - 161.119_57
+    } else {
+        288.122_17 * (t - 60.0).powf(-0.075_514_846)
+    };
+    let b = if t >= 66.0 {
+        255.0
+    } else if t <= 19.0 {
+        0.0
+    } else {
+        138.517_73 * (t - 10.0).ln() - 305.044_8
+    };
+    RGB8 {
+        r: clamp(r),
+        g: clamp(g),
+        b: clamp(b),
+    }
+}
+
+// Hue in turns (0 to 1), saturation and value between 0 and 1
+pub fn rgb_to_hsv(color: RGB8) -> (f32, f32, f32) {
+    let (r, g, b) = (color.r as f32 / 255.0, color.g as f32 / 255.0, color.b as f32 / 255.0);
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let delta = max - min;
+
+    let hue = if delta <= 0.0 {
+        0.0
+    } else if max == r {
+        ((g - b) / delta).rem_euclid(6.0) / 6.0
+    } else if max == g {
+        ((b - r) / delta + 2.0) / 6.0
+    } else {
+        ((r - g) / delta + 4.0) / 6.0
+    };
+    let saturation = if max <= 0.0 { 0.0 } else { delta / max };
+    (hue, saturation, max)
+}
+
+pub fn hsv_to_rgb(hue: f32, saturation: f32, value: f32) -> RGB8 {
+    let hue = hue.rem_euclid(1.0) * 6.0;
+    let chroma = value * saturation;
+    let x = chroma * (1.0 - (hue % 2.0 - 1.0).abs());
+    let (r, g, b) = match hue as u8 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = value - chroma;
+    RGB8 {
+        r: to_channel(r + m),
+        g: to_channel(g + m),
+        b: to_channel(b + m),
+    }
+}
+
+fn srgb_to_linear(channel: u8) -> f32 {
+    let c = channel as f32 / 255.0;
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(c: f32) -> u8 {
+    let c = c.max(0.0);
+    to_channel(if c <= 0.003_130_8 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    })
+}
+
+fn cbrt(x: f32) -> f32 {
+    if x <= 0.0 {
+        0.0
+    } else {
+        x.powf(1.0 / 3.0)
+    }
+}
+
+// L, a and b, from https://bottosson.github.io/posts/oklab/
+pub fn rgb_to_oklab(color: RGB8) -> [f32; 3] {
+    let (r, g, b) = (srgb_to_linear(color.r), srgb_to_linear(color.g), srgb_to_linear(color.b));
+    let l = cbrt(0.412_221_47 * r + 0.536_332_55 * g + 0.051_445_995 * b);
+    let m = cbrt(0.211_903_5 * r + 0.680_699_5 * g + 0.107_396_96 * b);
+    let s = cbrt(0.088_302_46 * r + 0.281_718_85 * g + 0.629_978_7 * b);
+    [
+        0.210_454_26 * l + 0.793_617_8 * m - 0.004_072_047 * s,
+        1.977_998_5 * l - 2.428_592_2 * m + 0.450_593_7 * s,
+        0.025_904_037 * l + 0.782_771_77 * m - 0.808_675_77 * s,
+    ]
+}
+
+pub fn oklab_to_rgb(lab: [f32; 3]) -> RGB8 {
+    let l = lab[0] + 0.396_337_78 * lab[1] + 0.215_803_76 * lab[2];
+    let m = lab[0] - 0.105_561_346 * lab[1] - 0.063_854_17 * lab[2];
+    let s = lab[0] - 0.089_484_18 * lab[1] - 1.291_485_5 * lab[2];
+    let (l, m, s) = (l * l * l, m * m * m, s * s * s);
+    RGB8 {
+        r: linear_to_srgb(4.076_741_7 * l - 3.307_711_6 * m + 0.230_969_94 * s),
+        g: linear_to_srgb(-1.268_438 * l + 2.609_757_4 * m - 0.341_319_4 * s),
+        b: linear_to_srgb(-0.004_196_086_3 * l - 0.703_418_6 * m + 1.707_614_7 * s),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: RGB8, b: RGB8, tolerance: i32) {
+        for (x, y) in [(a.r, b.r), (a.g, b.g), (a.b, b.b)] {
+            assert!((x as i32 - y as i32).abs() <= tolerance, "{:?} != {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn conversions_round_trip() {
+        for color in [
+            RGB8 { r: 255, g: 0, b: 0 },
+            RGB8 { r: 12, g: 200, b: 90 },
+            RGB8 { r: 255, g: 255, b: 255 },
+            RGB8 { r: 30, g: 30, b: 180 },
+        ] {
+            let (h, s, v) = rgb_to_hsv(color);
+            assert_close(hsv_to_rgb(h, s, v), color, 1);
+            // micromath's powf is an approximation
+            assert_close(oklab_to_rgb(rgb_to_oklab(color)), color, 4);
+        }
+    }
+
+    #[test]
+    fn hex_and_temperature_inputs() {
+        assert_eq!(parse_hex("#ff8000"), Some(RGB8 { r: 255, g: 128, b: 0 }));
+        assert_eq!(parse_hex("0a0B0c"), Some(RGB8 { r: 10, g: 11, b: 12 }));
+        assert_eq!(parse_hex("#f80"), Some(RGB8 { r: 255, g: 136, b: 0 }));
+        assert_eq!(parse_hex("#ff80"), None);
+        assert_eq!(parse_hex("#gg0000"), None);
+
+        // daylight is about white, a light bulb is orange
+        assert_close(kelvin_to_rgb(6600.0), RGB8 { r: 255, g: 255, b: 255 }, 10);
+        let bulb = kelvin_to_rgb(2700.0);
+        assert!(bulb.r == 255 && bulb.g < 200 && bulb.b < 120);
+
+        assert_close(rotate_hue(RGB8 { r: 200, g: 0, b: 0 }, 1.0 / 3.0), RGB8 { r: 0, g: 200, b: 0 }, 1);
+    }
+
+    #[test]
+    fn hsv_goes_the_short_way_around() {
+        let red = RGB8 { r: 255, g: 0, b: 0 };
+        let magenta = RGB8 { r: 255, g: 0, b: 255 };
+        // half way between them is a pinkish red, not green
+        let middle = mix(red, magenta, 0.5, Interpolation::Hsv);
+        assert_eq!(middle.g, 0);
+        assert_eq!(middle.r, 255);
+    }
+}
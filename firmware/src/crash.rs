@@ -0,0 +1,249 @@
+use core::fmt::Write;
+use core::panic::PanicInfo;
+
+use crate::json_events::Response;
+
+const CRASH_MAGIC: u32 = 0x4352_5348;
+const MAX_FILE_LEN: usize = 48;
+const MAX_MESSAGE_LEN: usize = 128;
+// crashing this many times without running stably in between puts the board in safe mode
+pub const SAFE_MODE_CRASHES: u32 = 3;
+// after this long without crashing the crash count is reset
+pub const STABLE_SECONDS: f32 = 60.0;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum ResetCause {
+    PowerOn,
+    Watchdog,
+    Panic,
+    Other,
+}
+
+impl ResetCause {
+    fn name(&self) -> &'static str {
+        match self {
+            ResetCause::PowerOn => "power_on",
+            ResetCause::Watchdog => "watchdog",
+            ResetCause::Panic => "panic",
+            ResetCause::Other => "other",
+        }
+    }
+}
+
+// Kept in RAM which isn't zeroed on start up, so the panic handler can leave it for the next boot.
+// Only plain integers, so whatever garbage is there after a power on is still a valid value.
+// The panic stays readable until the host clears it, across any number of soft resets.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct CrashRecord {
+    magic: u32,
+    crash_count: u32,
+    has_panic: u32,
+    // set by the panic handler, cleared once the next boot has seen it
+    panicked_last_run: u32,
+    line: u32,
+    column: u32,
+    file_len: u32,
+    file: [u8; MAX_FILE_LEN],
+    message_len: u32,
+    message: [u8; MAX_MESSAGE_LEN],
+}
+
+// Formats into the record's message buffer, silently cutting off whatever doesn't fit
+struct MessageWriter<'a> {
+    buffer: &'a mut [u8; MAX_MESSAGE_LEN],
+    len: usize,
+}
+
+impl<'a> Write for MessageWriter<'a> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for byte in s.bytes() {
+            if self.len == MAX_MESSAGE_LEN {
+                break;
+            }
+            self.buffer[self.len] = json_safe(byte);
+            self.len += 1;
+        }
+        Ok(())
+    }
+}
+
+// The message and file end up inside JSON strings, the bytes which would need escaping there are
+// replaced instead
+fn json_safe(byte: u8) -> u8 {
+    match byte {
+        b'"' => b'\'',
+        b'\\' | b'\n' | b'\r' | b'\t' => b' ',
+        byte => byte,
+    }
+}
+
+impl CrashRecord {
+    pub const fn empty() -> Self {
+        CrashRecord {
+            magic: CRASH_MAGIC,
+            crash_count: 0,
+            has_panic: 0,
+            panicked_last_run: 0,
+            line: 0,
+            column: 0,
+            file_len: 0,
+            file: [0; MAX_FILE_LEN],
+            message_len: 0,
+            message: [0; MAX_MESSAGE_LEN],
+        }
+    }
+
+    // Must be called once at boot, before anything else touches the record
+    pub fn validate(&mut self) {
+        if self.magic != CRASH_MAGIC
+            || self.file_len as usize > MAX_FILE_LEN
+            || self.message_len as usize > MAX_MESSAGE_LEN
+        {
+            *self = CrashRecord::empty();
+        }
+    }
+
+    pub fn record_panic(&mut self, info: &PanicInfo) {
+        self.validate();
+        self.crash_count += 1;
+        self.has_panic = 1;
+        self.panicked_last_run = 1;
+        self.line = 0;
+        self.column = 0;
+        self.file_len = 0;
+
+        let mut writer = MessageWriter {
+            buffer: &mut self.message,
+            len: 0,
+        };
+        let _ = write!(writer, "{}", info.message());
+        self.message_len = writer.len as u32;
+
+        if let Some(location) = info.location() {
+            let file = location.file().as_bytes();
+            // keep the end of the path, that's the part which tells files apart
+            let file = &file[file.len().saturating_sub(MAX_FILE_LEN)..];
+            for (stored, byte) in self.file.iter_mut().zip(file) {
+                *stored = json_safe(*byte);
+            }
+            self.file_len = file.len() as u32;
+            self.line = location.line();
+            self.column = location.column();
+        }
+    }
+
+    pub fn record_watchdog(&mut self) {
+        self.crash_count += 1;
+    }
+
+    pub fn has_panic(&self) -> bool {
+        self.has_panic != 0
+    }
+
+    pub fn crash_count(&self) -> u32 {
+        self.crash_count
+    }
+
+    pub fn mark_stable(&mut self) {
+        self.crash_count = 0;
+    }
+
+    // Forgets the stored panic, the crash count is kept for the safe mode
+    pub fn clear_panic(&mut self) {
+        self.has_panic = 0;
+        self.panicked_last_run = 0;
+        self.file_len = 0;
+        self.message_len = 0;
+    }
+
+    pub fn file(&self) -> &str {
+        core::str::from_utf8(&self.file[..self.file_len as usize]).unwrap_or("?")
+    }
+
+    pub fn message(&self) -> &str {
+        core::str::from_utf8(&self.message[..self.message_len as usize]).unwrap_or("?")
+    }
+
+    fn write_location(&self, response: &mut Response) {
+        let _ = write!(
+            response,
+            "\"file\":\"{}\",\"line\":{},\"column\":{},\"message\":\"{}\"",
+            self.file(),
+            self.line,
+            self.column,
+            self.message(),
+        );
+    }
+
+    // Answer to the "crash_log" command
+    pub fn write_json(&self, response: &mut Response) {
+        let _ = write!(
+            response,
+            "{{\"type\":\"crash_log\",\"panic\":{},\"crash_count\":{}",
+            self.has_panic(),
+            self.crash_count,
+        );
+        if self.has_panic() {
+            let _ = write!(response, ",");
+            self.write_location(response);
+        }
+        let _ = writeln!(response, "}}");
+    }
+}
+
+// What the status command reports about the reset which started the current run
+#[derive(Copy, Clone)]
+pub struct CrashReport {
+    pub cause: ResetCause,
+    pub record: CrashRecord,
+    pub safe_mode: bool,
+}
+
+impl CrashReport {
+    pub const fn none() -> Self {
+        CrashReport {
+            cause: ResetCause::PowerOn,
+            record: CrashRecord::empty(),
+            safe_mode: false,
+        }
+    }
+
+    // Works out why we booted, from the reset flags and the record left behind by the panic handler
+    pub fn from_reset(record: &mut CrashRecord, watchdog_reset: bool, power_on_reset: bool) -> Self {
+        record.validate();
+        let cause = if watchdog_reset {
+            record.record_watchdog();
+            ResetCause::Watchdog
+        } else if record.panicked_last_run != 0 {
+            ResetCause::Panic
+        } else if power_on_reset {
+            ResetCause::PowerOn
+        } else {
+            ResetCause::Other
+        };
+
+        let report = CrashReport {
+            cause,
+            record: *record,
+            safe_mode: record.crash_count() >= SAFE_MODE_CRASHES,
+        };
+        record.panicked_last_run = 0;
+        report
+    }
+
+    pub fn write_json(&self, response: &mut Response) {
+        let _ = write!(
+            response,
+            "{{\"cause\":\"{}\",\"crash_count\":{},\"safe_mode\":{}",
+            self.cause.name(),
+            self.record.crash_count(),
+            self.safe_mode,
+        );
+        if self.cause == ResetCause::Panic {
+            let _ = write!(response, ",");
+            self.record.write_location(response);
+        }
+        let _ = write!(response, "}}");
+    }
+}
@@ -0,0 +1,361 @@
+use core::ops::{Add, Div, Mul, Rem, Sub};
+
+use crate::new_strips::STRIP_LENGTH;
+use crate::behaviours::{message_pixel_idx, message_window};
+use crate::structs::{AttackDecayEvent, ConstantEvent, HeartbeatEvent, MessageEvent};
+use smart_leds_trait::RGB8;
+
+// Integer versions of the painters in behaviours.rs, used instead of them with the "fixed-point"
+// feature. The event's floats are converted once per event, everything done per pixel is integer
+// maths, and the cosine comes from a table. They're kept in step with the float painters by the
+// tests at the bottom.
+
+const TIME_THRESHOLD: Fixed = Fixed(3277); // 0.05
+
+// Q16.16, plenty for pixel positions and the few minutes an event lasts
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Fixed(i32);
+
+const FRAC_BITS: u32 = 16;
+const ZERO: Fixed = Fixed(0);
+const ONE: Fixed = Fixed(1 << FRAC_BITS);
+
+impl Fixed {
+    pub fn from_f32(value: f32) -> Self {
+        Fixed((value * ONE.0 as f32) as i32)
+    }
+
+    pub const fn from_int(value: i32) -> Self {
+        Fixed(value << FRAC_BITS)
+    }
+
+    fn floor(self) -> i32 {
+        self.0 >> FRAC_BITS
+    }
+
+    fn ceil(self) -> i32 {
+        (self.0 + ONE.0 - 1) >> FRAC_BITS
+    }
+
+    fn abs(self) -> Self {
+        Fixed(self.0.abs())
+    }
+
+    // a / b clamped to 1, like (a / b).min(1.0) which is also 1 for b == 0
+    fn ratio(self, divisor: Fixed) -> Self {
+        if divisor.0 <= 0 {
+            return ONE;
+        }
+        (self / divisor).min(ONE)
+    }
+}
+
+impl Add for Fixed {
+    type Output = Fixed;
+    fn add(self, other: Fixed) -> Fixed {
+        Fixed(self.0 + other.0)
+    }
+}
+
+impl Sub for Fixed {
+    type Output = Fixed;
+    fn sub(self, other: Fixed) -> Fixed {
+        Fixed(self.0 - other.0)
+    }
+}
+
+impl Mul for Fixed {
+    type Output = Fixed;
+    fn mul(self, other: Fixed) -> Fixed {
+        Fixed(((self.0 as i64 * other.0 as i64) >> FRAC_BITS) as i32)
+    }
+}
+
+impl Div for Fixed {
+    type Output = Fixed;
+    fn div(self, other: Fixed) -> Fixed {
+        Fixed((((self.0 as i64) << FRAC_BITS) / other.0 as i64) as i32)
+    }
+}
+
+impl Rem for Fixed {
+    type Output = Fixed;
+    fn rem(self, other: Fixed) -> Fixed {
+        Fixed(self.0 % other.0)
+    }
+}
+
+// cos(i / 64 * PI / 2) in Q16.16
+const COS_TABLE: [i32; 65] = [
+    65536, 65516, 65457, 65358, 65220, 65043, 64827, 64571,
+    64277, 63944, 63572, 63162, 62714, 62228, 61705, 61145,
+    60547, 59914, 59244, 58538, 57798, 57022, 56212, 55368,
+    54491, 53581, 52639, 51665, 50660, 49624, 48559, 47464,
+    46341, 45190, 44011, 42806, 41576, 40320, 39040, 37736,
+    36410, 35062, 33692, 32303, 30893, 29466, 28020, 26558,
+    25080, 23586, 22078, 20557, 19024, 17479, 15924, 14359,
+    12785, 11204, 9616, 8022, 6424, 4821, 3216, 1608,
+    0,
+];
+
+// cos(fraction * PI / 2) for a fraction between 0 and 1, interpolated between the table entries
+fn cos_quarter(fraction: Fixed) -> Fixed {
+    let fraction = fraction.max(ZERO).min(ONE).0;
+    let idx = (fraction >> 10) as usize;
+    if idx >= 64 {
+        return ZERO;
+    }
+    let remainder = fraction & 0x3ff;
+    let (a, b) = (COS_TABLE[idx], COS_TABLE[idx + 1]);
+    Fixed(a + (((b - a) * remainder) >> 10))
+}
+
+pub fn paint_message_event(
+    strip: &mut [RGB8; STRIP_LENGTH],
+    event: &MessageEvent,
+    elapsed_time_seconds: f32,
+) {
+    // comet tails need exp, they're rare enough to leave to the float painter
+    if event.tail_length > 0 {
+        return crate::behaviours::paint_message_event(strip, event, elapsed_time_seconds);
+    }
+
+    let elapsed = Fixed::from_f32(elapsed_time_seconds);
+    if elapsed < TIME_THRESHOLD {
+        return;
+    }
+
+    let event_position = elapsed * Fixed::from_f32(event.pace);
+    let half_width = Fixed(((event.message_width as i32) << FRAC_BITS) / 2);
+    let (first, last) = message_window(
+        event,
+        (event_position - half_width).ceil(),
+        (event_position + half_width).floor(),
+    );
+
+    for pixel_position in first..=last {
+        let idx = message_pixel_idx(event, pixel_position);
+        let intensity =
+            get_message_pixel_intensity(Fixed::from_int(pixel_position), event_position, half_width);
+        strip[idx] = add_color(strip[idx], event.color, intensity);
+    }
+}
+
+fn get_message_pixel_intensity(
+    pixel_position: Fixed,
+    event_position: Fixed,
+    half_width: Fixed,
+) -> Fixed {
+    let distance = (pixel_position - event_position).abs();
+    if distance > half_width || half_width == ZERO {
+        return ZERO;
+    }
+    cos_quarter(distance / half_width)
+}
+
+pub fn paint_solid_pixel(
+    strip: &mut [RGB8; STRIP_LENGTH],
+    event: &ConstantEvent,
+    _elapsed_time_seconds: f32,
+) {
+    // like the float painter, the fades aren't applied yet
+    for idx in event.pixels.indices() {
+        strip[idx] = add_color(strip[idx], event.color, ONE);
+    }
+}
+
+pub fn paint_heartbeat_pixel(
+    strip: &mut [RGB8; STRIP_LENGTH],
+    event: &HeartbeatEvent,
+    elapsed_time_seconds: f32,
+) {
+    let elapsed = Fixed::from_f32(elapsed_time_seconds);
+    if elapsed > Fixed::from_f32(event.duration) {
+        return;
+    }
+
+    let dimness = Fixed::from_f32(event.dimness);
+    let loop_duration = Fixed::from_f32(event.loop_duration);
+    let first_attack = Fixed::from_f32(event.first_pulse_attack);
+    let first_decay = Fixed::from_f32(event.first_pulse_decay);
+    let second_attack = Fixed::from_f32(event.second_pulse_attack);
+    let second_decay = Fixed::from_f32(event.second_pulse_decay);
+
+    let mut intensity = dimness;
+    if loop_duration > ZERO {
+        let time_in_loop = elapsed % loop_duration;
+        let range = ONE - dimness;
+
+        if time_in_loop < first_attack {
+            intensity = intensity + range * time_in_loop.ratio(first_attack);
+        } else if time_in_loop < first_attack + first_decay {
+            let decay_time = time_in_loop - first_attack;
+            intensity = ONE - range * decay_time.ratio(first_decay);
+        } else if time_in_loop < first_attack + first_decay + second_attack {
+            let attack_time = time_in_loop - (first_attack + first_decay);
+            intensity = intensity + range * attack_time.ratio(second_attack);
+        } else if time_in_loop < first_attack + first_decay + second_attack + second_decay {
+            let decay_time = time_in_loop - (first_attack + first_decay + second_attack);
+            intensity = ONE - range * decay_time.ratio(second_decay);
+        }
+    }
+
+    for idx in event.pixels.indices() {
+        strip[idx] = add_color(strip[idx], event.color, intensity);
+    }
+}
+
+pub fn paint_attack_decay_event(
+    strip: &mut [RGB8; STRIP_LENGTH],
+    event: &AttackDecayEvent,
+    elapsed_time_seconds: f32,
+) {
+    let attack = Fixed::from_f32(event.attack_duration);
+    let decay = Fixed::from_f32(event.decay_duration);
+    let total_duration = attack + decay;
+    if total_duration <= ZERO {
+        return;
+    }
+    let normalized_time = Fixed::from_f32(elapsed_time_seconds) % total_duration;
+
+    let level = if normalized_time < attack {
+        normalized_time / attack
+    } else {
+        ONE - (normalized_time - attack) / decay
+    };
+
+    let start = Fixed::from_int(event.start_idx as i32);
+    let fill_idx = start + level * (Fixed::from_int(event.end_idx as i32) - start);
+    let smoothing = Fixed::from_f32(event.smoothing_factor);
+
+    for (idx, pixel) in strip.iter_mut().enumerate().take(event.end_idx + 1).skip(event.start_idx) {
+        let position = Fixed::from_int(idx as i32);
+        let dist_to_fill = (fill_idx - position).abs();
+        let intensity = if dist_to_fill < smoothing {
+            ONE - dist_to_fill / smoothing
+        } else if position <= fill_idx {
+            ONE
+        } else {
+            ZERO
+        };
+
+        *pixel = add_color(*pixel, event.color, intensity);
+    }
+}
+
+fn add_channel(current: u8, new: u8, intensity: Fixed) -> u8 {
+    let added = (new as i32 * intensity.0 + (1 << (FRAC_BITS - 1))) >> FRAC_BITS;
+    (current as i32 + added).clamp(0, 255) as u8
+}
+
+fn add_color(current_color: RGB8, new_color: RGB8, intensity: Fixed) -> RGB8 {
+    let intensity = intensity.max(ZERO).min(ONE);
+    RGB8 {
+        r: add_channel(current_color.r, new_color.r, intensity),
+        g: add_channel(current_color.g, new_color.g, intensity),
+        b: add_channel(current_color.b, new_color.b, intensity),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::behaviours;
+    use crate::structs::PixelRange;
+
+    const BLACK: RGB8 = RGB8 { r: 0, g: 0, b: 0 };
+    const COLOR: RGB8 = RGB8 { r: 255, g: 120, b: 7 };
+
+    // Rounding may differ in the last bit, anything more is a bug in one of the paths
+    fn assert_close(float: &[RGB8; STRIP_LENGTH], fixed: &[RGB8; STRIP_LENGTH], time: f32) {
+        for (idx, (a, b)) in float.iter().zip(fixed.iter()).enumerate() {
+            for (x, y) in [(a.r, b.r), (a.g, b.g), (a.b, b.b)] {
+                assert!(
+                    (x as i32 - y as i32).abs() <= 1,
+                    "pixel {} at {}s: float {:?} fixed {:?}",
+                    idx, time, a, b,
+                );
+            }
+        }
+    }
+
+    // an odd step, the attack decay painter jumps where the fill lands exactly on a pixel
+    fn times() -> impl Iterator<Item = f32> {
+        (0..400).map(|i| i as f32 * 0.0371)
+    }
+
+    #[test]
+    fn message_matches_float_path() {
+        for (start_idx, end_idx) in [(10, 150), (150, 10)] {
+            let event = MessageEvent {
+                color: COLOR,
+                message_width: 9,
+                pace: 13.5,
+                strip_idx: 0,
+                start_idx,
+                end_idx,
+                tail_length: 0,
+                tail_color: RGB8 { r: 0, g: 0, b: 0 },
+                tail_follows_color: false,
+            };
+            for time in times() {
+                let (mut float, mut fixed) = ([BLACK; STRIP_LENGTH], [BLACK; STRIP_LENGTH]);
+                behaviours::paint_message_event(&mut float, &event, time);
+                paint_message_event(&mut fixed, &event, time);
+                assert_close(&float, &fixed, time);
+            }
+        }
+    }
+
+    #[test]
+    fn heartbeat_matches_float_path() {
+        let event = HeartbeatEvent {
+            color: COLOR,
+            duration: 12.0,
+            strip_idx: 0,
+            pixels: PixelRange { start: 3, end: 9 },
+            first_pulse_attack: 0.1,
+            first_pulse_decay: 0.3,
+            second_pulse_attack: 0.15,
+            second_pulse_decay: 0.6,
+            loop_duration: 1.7,
+            dimness: 0.2,
+        };
+        for time in times() {
+            let (mut float, mut fixed) = ([BLACK; STRIP_LENGTH], [BLACK; STRIP_LENGTH]);
+            behaviours::paint_heartbeat_pixel(&mut float, &event, time);
+            paint_heartbeat_pixel(&mut fixed, &event, time);
+            assert_close(&float, &fixed, time);
+        }
+    }
+
+    #[test]
+    fn constant_and_attack_decay_match_float_path() {
+        let constant = ConstantEvent {
+            color: COLOR,
+            duration: 5.0,
+            fadein_duration: 1,
+            fadeout_duration: 1,
+            fade_power: 2,
+            strip_idx: 0,
+            pixels: PixelRange { start: 40, end: 44 },
+        };
+        let attack_decay = AttackDecayEvent {
+            color: COLOR,
+            attack_duration: 1.5,
+            decay_duration: 2.5,
+            smoothing_factor: 4.0,
+            strip_idx: 0,
+            start_idx: 20,
+            end_idx: 120,
+        };
+        for time in times() {
+            let (mut float, mut fixed) = ([BLACK; STRIP_LENGTH], [BLACK; STRIP_LENGTH]);
+            behaviours::paint_solid_pixel(&mut float, &constant, time);
+            paint_solid_pixel(&mut fixed, &constant, time);
+            behaviours::paint_attack_decay_event(&mut float, &attack_decay, time);
+            paint_attack_decay_event(&mut fixed, &attack_decay, time);
+            assert_close(&float, &fixed, time);
+        }
+    }
+}
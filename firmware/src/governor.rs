@@ -0,0 +1,116 @@
+use core::fmt::Write;
+
+use crate::json_events::Response;
+
+pub const DEFAULT_TARGET_FPS: u32 = 60;
+// the frame timer is 16 bits, so it can't count much slower than this
+pub const MIN_TARGET_FPS: u32 = 5;
+pub const MAX_TARGET_FPS: u32 = 240;
+
+// Keeps the frames on a fixed grid of the target frame rate, which the hardware paces with a timer.
+// Animations are rendered at the time of the grid slot instead of whenever the frame happened to
+// start, so a busy frame doesn't make the motion stutter. When a frame takes longer than a slot,
+// the slots it overran are skipped rather than rendered late, so motion speed stays the same.
+// The host changes the rate with {"type":"frame_rate","fps":60,"interpolate":false}
+pub struct FrameGovernor {
+    target_fps: u32,
+    // render at the real time within the slot instead of the start of the slot
+    pub interpolate: bool,
+    next_deadline: Option<f32>,
+    pub skipped_frames: u32,
+    // frames which were still rendering when their slot ended
+    pub late_frames: u32,
+}
+
+impl Default for FrameGovernor {
+    fn default() -> Self {
+        FrameGovernor::new()
+    }
+}
+
+impl FrameGovernor {
+    pub const fn new() -> Self {
+        FrameGovernor {
+            target_fps: DEFAULT_TARGET_FPS,
+            interpolate: false,
+            next_deadline: None,
+            skipped_frames: 0,
+            late_frames: 0,
+        }
+    }
+
+    pub fn target_fps(&self) -> u32 {
+        self.target_fps
+    }
+
+    pub fn frame_seconds(&self) -> f32 {
+        1.0 / self.target_fps as f32
+    }
+
+    pub fn set_target_fps(&mut self, fps: u32) {
+        self.target_fps = fps.clamp(MIN_TARGET_FPS, MAX_TARGET_FPS);
+        // start a new grid, the old deadlines mean nothing at the new rate
+        self.next_deadline = None;
+    }
+
+    // Call at the start of every frame with the real time, returns the time to render the frame at
+    pub fn start_frame(&mut self, real_seconds: f32) -> f32 {
+        let frame_seconds = self.frame_seconds();
+        let slot_start = match self.next_deadline {
+            Some(deadline) if real_seconds >= deadline => {
+                let slots = ((real_seconds - deadline) / frame_seconds) as u32;
+                self.skipped_frames += slots;
+                deadline + slots as f32 * frame_seconds
+            }
+            // the frame timer and the real time clock drift apart a little, trust the timer
+            Some(deadline) => deadline,
+            None => real_seconds,
+        };
+        self.next_deadline = Some(slot_start + frame_seconds);
+
+        if self.interpolate {
+            real_seconds.max(slot_start)
+        } else {
+            slot_start
+        }
+    }
+
+    // Call once the frame has been written out, with the real time
+    pub fn end_frame(&mut self, real_seconds: f32) {
+        if let Some(deadline) = self.next_deadline {
+            if real_seconds > deadline {
+                self.late_frames += 1;
+            }
+        }
+    }
+
+    pub fn write_json(&self, response: &mut Response) {
+        let _ = write!(
+            response,
+            "{{\"target_fps\":{},\"interpolate\":{},\"skipped_frames\":{},\"late_frames\":{}}}",
+            self.target_fps, self.interpolate, self.skipped_frames, self.late_frames,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overrun_frames_are_skipped() {
+        let mut governor = FrameGovernor::new();
+        governor.set_target_fps(10);
+
+        assert_eq!(governor.start_frame(1.0), 1.0);
+        governor.end_frame(1.05);
+        // this frame took three and a half slots
+        assert_eq!(governor.start_frame(1.12), 1.1);
+        governor.end_frame(1.45);
+        assert_eq!(governor.late_frames, 1);
+
+        let rendered = governor.start_frame(1.45);
+        assert!((rendered - 1.4).abs() < 1e-4);
+        assert_eq!(governor.skipped_frames, 2);
+    }
+}
@@ -0,0 +1,209 @@
+use crate::{
+    clock::{TimeBase, Timestamp},
+    json_events::{add_event, CommandError, Node},
+    new_strips::{push_event, PushReport, MAX_EVENTS, STRIP_INDICES},
+    storage::{Slot, Storage},
+    structs::{Event, EventWrapper, LoopMode, MessageEvent},
+};
+use heapless::Vec;
+use microjson::JSONValue;
+use smart_leds_trait::RGB8;
+
+pub const DEFAULT_IDLE_TIMEOUT: f32 = 10.0;
+
+// Attract animation which plays whenever the host has not sent any events for a while. The timeout
+// is measured in real seconds, so the scene still starts while the clock is paused or slowed down.
+// The host can replace it with the "idle_scene" command, which is kept in flash:
+// {"type":"idle_scene","timeout":30,"events":[{"type":"message",...},{"type":"heartbeat",...}]}
+pub struct IdleScene {
+    timeout_seconds: f32,
+    last_command_seconds: f32,
+    playing: bool,
+    // the scene added no events for our strips, so there is nothing to wait for
+    empty: bool,
+    // ignore the uploaded scene, in case it's what keeps crashing the board
+    pub safe_mode: bool,
+}
+
+impl Default for IdleScene {
+    fn default() -> Self {
+        IdleScene::new()
+    }
+}
+
+impl IdleScene {
+    pub const fn new() -> Self {
+        IdleScene {
+            timeout_seconds: DEFAULT_IDLE_TIMEOUT,
+            last_command_seconds: 0.0,
+            playing: false,
+            empty: false,
+            safe_mode: false,
+        }
+    }
+
+    // Picks up the timeout of the scene stored in flash, call once at boot
+    pub fn load(&mut self, storage: &dyn Storage) {
+        if let Some(scene) = self.stored_scene(storage) {
+            if let Ok(json) = JSONValue::parse(scene) {
+                if let Ok(timeout) = json.get_key_value("timeout").and_then(|t| t.read_float()) {
+                    self.timeout_seconds = timeout;
+                }
+            }
+        }
+    }
+
+    fn stored_scene<'a>(&self, storage: &'a dyn Storage) -> Option<&'a str> {
+        if self.safe_mode {
+            return None;
+        }
+        storage
+            .load(Slot::IdleScene)
+            .and_then(|scene| core::str::from_utf8(scene).ok())
+    }
+
+    // Stores the whole command line, so the scene can later be replayed exactly as it was sent
+    pub fn configure(
+        &mut self,
+        json: &JSONValue,
+        json_str: &str,
+        storage: &mut dyn Storage,
+        events: &mut Vec<EventWrapper, MAX_EVENTS>,
+    ) -> Result<(), CommandError> {
+        let timeout_seconds = json.get_key_value("timeout")?.read_float()?;
+        json.get_key_value("events")?.iter_array()?;
+        storage.store(Slot::IdleScene, json_str.trim_end().as_bytes())?;
+
+        self.timeout_seconds = timeout_seconds;
+        // the new scene takes over the next time we're idle
+        self.stop(events);
+        Ok(())
+    }
+
+    // Live events from the host always win, the idle scene makes way for them
+    pub fn interrupt(&mut self, real_seconds: f32, events: &mut Vec<EventWrapper, MAX_EVENTS>) {
+        self.last_command_seconds = real_seconds;
+        self.stop(events);
+    }
+
+    fn stop(&mut self, events: &mut Vec<EventWrapper, MAX_EVENTS>) {
+        if self.playing {
+            events.retain(|event| !event.idle);
+            self.playing = false;
+        }
+    }
+
+    // Call every frame: starts the scene after the timeout and starts it over once it has finished
+    pub fn update(
+        &mut self,
+        now: Timestamp,
+        real_seconds: f32,
+        events: &mut Vec<EventWrapper, MAX_EVENTS>,
+        storage: &dyn Storage,
+    ) {
+        if real_seconds - self.last_command_seconds < self.timeout_seconds {
+            return;
+        }
+        if self.playing && (self.empty || events.iter().any(|event| event.idle)) {
+            return;
+        }
+
+        let played = match self.stored_scene(storage) {
+            Some(scene) => play_stored_scene(scene, now, events).is_ok(),
+            None => false,
+        };
+        if !played {
+            events.retain(|event| !event.idle);
+            add_default_scene(now, events);
+        }
+        self.playing = true;
+        self.empty = !events.iter().any(|event| event.idle);
+    }
+}
+
+fn play_stored_scene(
+    scene: &str,
+    now: Timestamp,
+    events: &mut Vec<EventWrapper, MAX_EVENTS>,
+) -> Result<(), CommandError> {
+    let json = JSONValue::parse(scene)?;
+    let mut report = PushReport::default();
+    for event in json.get_key_value("events")?.iter_array()? {
+        let event_type = event.get_key_value("type")?;
+        let event_type = event_type.read_string()?;
+        // whatever the scene asks for, its events get the lowest priority
+        let node = Node {
+            json: event,
+            defaults: None,
+            idle: true,
+        };
+        add_event(&node, event_type, now, events, &mut report)?;
+    }
+    Ok(())
+}
+
+// Red messages bouncing back and forth along both strips
+fn add_default_scene(now: Timestamp, events: &mut Vec<EventWrapper, MAX_EVENTS>) {
+    for strip_idx in [STRIP_INDICES.0, STRIP_INDICES.1] {
+        push_event(events, EventWrapper {
+            event: Event::Message(MessageEvent {
+                color: RGB8 { r: 100, g: 0, b: 0 },
+                pace: 20.0,
+                message_width: 7,
+                strip_idx,
+                start_idx: 0,
+                end_idx: 99,
+                tail_length: 0,
+                tail_color: RGB8 { r: 0, g: 0, b: 0 },
+                tail_follows_color: false,
+            }),
+            start_time: Some(now.seconds),
+            loop_mode: LoopMode::PingPong,
+            id: None,
+            time_base: TimeBase::Seconds,
+            time_scale: 1.0,
+            idle: true,
+            priority: 0,
+            palette: None,
+            hue_rotation: 0.0,
+        }, &mut PushReport::default());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::VirtualClock, storage::StorageError};
+
+    struct NoStorage;
+
+    impl Storage for NoStorage {
+        fn load(&self, _slot: Slot) -> Option<&[u8]> {
+            None
+        }
+
+        fn store(&mut self, _slot: Slot, _data: &[u8]) -> Result<(), StorageError> {
+            Err(StorageError::Flash)
+        }
+    }
+
+    #[test]
+    fn times_out_while_paused() {
+        let mut clock = VirtualClock::new();
+        let mut idle_scene = IdleScene::new();
+        let mut events = Vec::new();
+        clock.tick(0.0);
+        idle_scene.interrupt(0.0, &mut events);
+        clock.pause();
+
+        let real_seconds = DEFAULT_IDLE_TIMEOUT - 1.0;
+        idle_scene.update(clock.tick(real_seconds), real_seconds, &mut events, &NoStorage);
+        assert!(events.is_empty());
+
+        let real_seconds = DEFAULT_IDLE_TIMEOUT + 1.0;
+        let now = clock.tick(real_seconds);
+        idle_scene.update(now, real_seconds, &mut events, &NoStorage);
+        assert_eq!(now.seconds, 0.0);
+        assert!(!events.is_empty() && events.iter().all(|event| event.idle));
+    }
+}
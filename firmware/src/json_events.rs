@@ -1,267 +1,1327 @@
+use core::fmt::Write;
+
 use crate::{
-    new_strips::{MAX_EVENTS, STRIP_INDICES},
-    structs::{ConstantEvent, Event, EventWrapper, MessageEvent, HeartbeatEvent},
+    crash::CrashRecord,
+    clock::{TimeBase, Timestamp, VirtualClock, DEFAULT_STEP_SECONDS},
+    color::{hsv_to_rgb, kelvin_to_rgb, parse_hex, Interpolation},
+    governor::FrameGovernor,
+    idle_scene::IdleScene,
+    new_strips::{push_event, PushReport, DEFAULT_PRIORITY, MAX_EVENTS, STRIP_INDICES, STRIP_LENGTH},
+    palettes::{name_hash, PalettePick, PaletteRef, Palettes},
+    presets::{define_preset, delete_preset, find_preset},
+    storage::{SlotBuffer, Storage, StorageError},
+    stream::StreamLayer,
+    structs::{
+        ConstantEvent, Event, EventWrapper, GradientEvent, GradientStop, GrowEvent, HeartbeatEvent,
+        LoopMode, MessageEvent, NoiseEvent, PixelRange, RippleEvent, SparkleEvent, MAX_GRADIENT_STOPS,
+        MAX_COLORS, MAX_GROW_BRANCHES,
+    },
+    telemetry::{write_hello, Telemetry},
 };
-use heapless::Vec;
+use heapless::{String, Vec};
 use micromath::F32Ext;
-use microjson::{JSONValue, JSONValueType};
+use microjson::{JSONParsingError, JSONValue, JSONValueType};
 use smart_leds_trait::RGB8;
 
+// bumped whenever a change to the commands would break the controller
+pub const PROTOCOL_VERSION: u32 = 1;
+// longest command line the board can receive
+pub const MAX_LINE_LEN: usize = 4096 * 2;
+pub const EVENT_TYPES: [&str; 8] =
+    ["message", "constant", "heartbeat", "gradient", "sparkle", "ripple", "noise", "grow"];
+pub const MAX_RESPONSE_LEN: usize = 1024;
+pub type Response = String<MAX_RESPONSE_LEN>;
+
+#[derive(Debug)]
+pub enum CommandError {
+    Json(JSONParsingError),
+    UnknownType,
+    InvalidColor,
+    UnknownPreset,
+    InvalidGradient,
+    InvalidSpeed,
+    InvalidPalette,
+    InvalidPath,
+    Storage(StorageError),
+}
+
+impl From<JSONParsingError> for CommandError {
+    fn from(error: JSONParsingError) -> Self {
+        CommandError::Json(error)
+    }
+}
+
+impl From<StorageError> for CommandError {
+    fn from(error: StorageError) -> Self {
+        CommandError::Storage(error)
+    }
+}
+
+// An event's JSON object, optionally on top of a preset which fills in the keys it leaves out
+#[derive(Copy, Clone)]
+pub struct Node<'a> {
+    pub json: JSONValue<'a>,
+    pub defaults: Option<JSONValue<'a>>,
+    // part of the idle scene, whose events always have the lowest priority
+    pub idle: bool,
+}
+
+impl<'a> Node<'a> {
+    pub fn new(json: JSONValue<'a>) -> Self {
+        Node {
+            json,
+            defaults: None,
+            idle: false,
+        }
+    }
+
+    pub fn get_key_value(&self, key: &str) -> Result<JSONValue<'_>, JSONParsingError> {
+        match (self.json.get_key_value(key), &self.defaults) {
+            (Err(_), Some(defaults)) => defaults.get_key_value(key),
+            (value, _) => value,
+        }
+    }
+}
+
+// Board state which commands can change, apart from the events themselves
+pub struct CommandContext<'a> {
+    pub clock: &'a mut VirtualClock,
+    pub telemetry: &'a mut Telemetry,
+    pub crash_record: &'a mut CrashRecord,
+    pub idle_scene: &'a mut IdleScene,
+    pub governor: &'a mut FrameGovernor,
+    pub palettes: &'a mut Palettes,
+    pub stream: &'a mut StreamLayer,
+    pub storage: &'a mut dyn Storage,
+    pub slot_buffer: &'a mut SlotBuffer,
+}
+
+// Runs a single JSON command, counting it in the telemetry. Returns the line to send back to the
+// host, if the command has one; failed commands are answered with an error line.
 pub fn add_events_from_json(
     events: &mut Vec<EventWrapper, MAX_EVENTS>,
+    context: &mut CommandContext,
+    json_str: &str,
+    now: Timestamp,
+    real_seconds: f32,
+) -> Option<Response> {
+    let mut response = Response::new();
+    match run_command(events, context, json_str, now, real_seconds, &mut response) {
+        Ok(()) => context.telemetry.commands += 1,
+        Err(error) => {
+            context.telemetry.parse_errors += 1;
+            response.clear();
+            let _ = writeln!(response, "{{\"type\":\"error\",\"error\":\"{:?}\"}}", error);
+        }
+    }
+
+    if response.is_empty() {
+        None
+    } else {
+        Some(response)
+    }
+}
+
+fn run_command(
+    events: &mut Vec<EventWrapper, MAX_EVENTS>,
+    context: &mut CommandContext,
     json_str: &str,
-    timer_seconds: f32,
-) -> () {
-    let json = JSONValue::parse(json_str).unwrap();
-    match json.get_key_value("type").unwrap().read_string().unwrap() {
-        "message" => {
-            process_message_node(&json, timer_seconds, events, true);
+    now: Timestamp,
+    real_seconds: f32,
+    response: &mut Response,
+) -> Result<(), CommandError> {
+    let json = JSONValue::parse(json_str)?;
+    let command_type = json.get_key_value("type")?;
+    let command_type = command_type.read_string()?;
+    let mut report = PushReport::default();
+    if EVENT_TYPES.contains(&command_type) {
+        context.idle_scene.interrupt(real_seconds, events);
+        add_event(&Node::new(json), command_type, now, events, &mut report)?;
+        write_push_report(&report, context.telemetry, response);
+        return Ok(());
+    }
+
+    let clock = &mut context.clock;
+    match command_type {
+        "hello" => {
+            write_hello(response);
+        }
+        "status" => {
+            context.telemetry.write_status(response, events.len(), context.governor);
+        }
+        "crash_log" => {
+            context.crash_record.write_json(response);
+        }
+        "clear_crash_log" => {
+            context.crash_record.clear_panic();
+        }
+        "preset" => {
+            let template = find_preset(context.storage, json.get_key_value("name")?.read_string()?)
+                .ok_or(CommandError::UnknownPreset)?;
+            let node = Node {
+                json,
+                defaults: Some(template),
+                idle: false,
+            };
+            context.idle_scene.interrupt(real_seconds, events);
+            add_event(&node, template.get_key_value("type")?.read_string()?, now, events, &mut report)?;
+            write_push_report(&report, context.telemetry, response);
+        }
+        "define_preset" => {
+            define_preset(context.storage, context.slot_buffer, &json, json_str)?;
+        }
+        "delete_preset" => {
+            delete_preset(context.storage, context.slot_buffer, json.get_key_value("name")?.read_string()?)?;
+        }
+        "define_palette" => {
+            context.palettes.define_palette(context.storage, context.slot_buffer, &json, json_str)?;
+        }
+        "delete_palette" => {
+            let name = json.get_key_value("name")?;
+            let name = name.read_string()?;
+            context.palettes.delete_palette(context.storage, context.slot_buffer, name)?;
+        }
+        // the frames themselves come as binary packets, see stream.rs
+        "stream" => {
+            if let Ok(timeout) = json.get_key_value("timeout") {
+                context.stream.timeout = timeout.read_float()?;
+            }
+            if let Ok(fade) = json.get_key_value("fade") {
+                context.stream.fade = fade.read_float()?;
+            }
+            context.stream.write_json(response);
+        }
+        "idle_scene" => {
+            context.idle_scene.configure(&json, json_str, context.storage, events)?;
         }
         "clear" => {
+            context.idle_scene.interrupt(real_seconds, events);
             events.clear();
         }
-        "constant" => {
-            process_constant_node(&json, timer_seconds, events, true);
+        "cancel" => {
+            let id = json.get_key_value("id")?.read_integer()? as u16;
+            events.retain(|event| event.id != Some(id));
+        }
+        "pause" => {
+            clock.pause();
+        }
+        "resume" => {
+            clock.resume();
+        }
+        "speed" => {
+            clock.set_speed(json.get_key_value("speed")?.read_float()?);
         }
-        "heartbeat" => {
-            process_heartbeat_node(&json, timer_seconds, events, true);
+        "tempo" => {
+            clock.set_bpm(json.get_key_value("bpm")?.read_float()?);
         }
-        _ => {},
+        "frame_rate" => {
+            context.governor.set_target_fps(json.get_key_value("fps")?.read_integer()? as u32);
+            if let Ok(interpolate) = json.get_key_value("interpolate") {
+                context.governor.interpolate = read_bool(&interpolate)?;
+            }
+        }
+        "step" => {
+            let frames = match json.get_key_value("frames") {
+                Ok(frames) => frames.read_integer()? as u32,
+                Err(_) => 1,
+            };
+            let frame_duration = match json.get_key_value("frame_duration") {
+                Ok(frame_duration) => frame_duration.read_float()?,
+                Err(_) => DEFAULT_STEP_SECONDS,
+            };
+            clock.step(frames, frame_duration);
+        }
+        _ => return Err(CommandError::UnknownType),
     };
+
+    Ok(())
+}
+
+// Lets the host know when events were lost because the queue was full
+fn write_push_report(report: &PushReport, telemetry: &mut Telemetry, response: &mut Response) {
+    if report.evicted == 0 && report.dropped == 0 {
+        return;
+    }
+
+    telemetry.evicted += report.evicted as u32;
+    telemetry.dropped += report.dropped as u32;
+    let _ = writeln!(
+        response,
+        "{{\"type\":\"evicted\",\"evicted\":{},\"dropped\":{}}}",
+        report.evicted, report.dropped,
+    );
+}
+
+// Adds the event described by a JSON object of one of the EVENT_TYPES
+pub fn add_event(
+    json: &Node,
+    event_type: &str,
+    now: Timestamp,
+    events: &mut Vec<EventWrapper, MAX_EVENTS>,
+    report: &mut PushReport,
+) -> Result<(), CommandError> {
+    match event_type {
+        "message" => process_message_node(json, now, events, report, true),
+        "constant" => process_constant_node(json, now, events, report),
+        "heartbeat" => process_heartbeat_node(json, now, events, report),
+        "gradient" => process_gradient_node(json, now, events, report),
+        "sparkle" => process_sparkle_node(json, now, events, report),
+        "ripple" => process_ripple_node(json, now, events, report),
+        "noise" => process_noise_node(json, now, events, report),
+        "grow" => process_grow_node(json, now, events, report),
+        _ => Err(CommandError::UnknownType),
+    }
 }
 
 fn process_message_node(
-    node: &JSONValue,
-    timer_seconds: f32,
+    node: &Node,
+    now: Timestamp,
     events: &mut Vec<EventWrapper, MAX_EVENTS>,
+    report: &mut PushReport,
     first_node: bool,
-) {
-    if node.value_type == JSONValueType::Null {
-        return;
+) -> Result<(), CommandError> {
+    if node.json.value_type == JSONValueType::Null {
+        return Ok(());
     }
 
     let strip_idx: usize = node
-        .get_key_value("strip_idx")
-        .unwrap()
-        .read_integer()
-        .unwrap() as usize;
+        .get_key_value("strip_idx")?
+        .read_integer()? as usize;
 
     if strip_idx == STRIP_INDICES.0 || strip_idx == STRIP_INDICES.1 {
-        events.push(EventWrapper {
-            start_time: if first_node { Some(timer_seconds) } else { None },
-            event: Event::Message(parse_message_event(&node)),
-        });
+        let time_base = parse_time_base(node)?;
+        push_event(events, EventWrapper {
+            start_time: if first_node { Some(now.in_base(time_base)) } else { None },
+            event: Event::Message(parse_message_event(node)?),
+            loop_mode: parse_loop_mode(node)?,
+            id: parse_id(node)?,
+            time_base,
+            time_scale: parse_time_scale(node)?,
+            idle: node.idle,
+            priority: parse_priority(node)?,
+            palette: parse_palette_ref(node)?,
+            hue_rotation: parse_hue_rotation(node)?,
+        }, report);
     }
 
-    let next = node.get_key_value("next").unwrap();
-    process_message_node(&next, timer_seconds, events, false);
+    match node.get_key_value("next") {
+        Ok(next) => {
+            let next = Node {
+                json: next,
+                defaults: None,
+                idle: node.idle,
+            };
+            process_message_node(&next, now, events, report, false)
+        }
+        Err(_) => Ok(()),
+    }
 }
 
-fn parse_message_event(json: &JSONValue) -> MessageEvent {
-    let color: Vec<u8, 3> = json
-        .get_key_value("color")
-        .unwrap()
-        .iter_array()
-        .unwrap()
-        .map(|x| x.read_integer().unwrap() as u8)
-        .collect();
+fn parse_message_event(json: &Node) -> Result<MessageEvent, CommandError> {
+    let color = parse_color(json)?;
 
-    MessageEvent {
-        color: smart_leds_trait::RGB {
-            r: color[0],
-            g: color[1],
-            b: color[2],
-        },
-        pace: json.get_key_value("pace").unwrap().read_float().unwrap() as f32,
+    Ok(MessageEvent {
+        color,
+        pace: json.get_key_value("pace")?.read_float()?,
         message_width: json
-            .get_key_value("message_width")
-            .unwrap()
-            .read_integer()
-            .unwrap() as u16,
+            .get_key_value("message_width")?
+            .read_integer()? as u16,
         strip_idx: json
-            .get_key_value("strip_idx")
-            .unwrap()
-            .read_integer()
-            .unwrap() as usize,
+            .get_key_value("strip_idx")?
+            .read_integer()? as usize,
         start_idx: json
-            .get_key_value("start_idx")
-            .unwrap()
-            .read_integer()
-            .unwrap() as usize,
+            .get_key_value("start_idx")?
+            .read_integer()? as usize,
         end_idx: json
-            .get_key_value("end_idx")
-            .unwrap()
-            .read_integer()
-            .unwrap() as usize,
+            .get_key_value("end_idx")?
+            .read_integer()? as usize,
+        tail_length: match json.get_key_value("tail_length") {
+            Ok(tail_length) => tail_length.read_integer()? as u16,
+            Err(_) => 0,
+        },
+        tail_color: match json.get_key_value("tail_color") {
+            Ok(tail_color) => read_color(&tail_color)?,
+            Err(_) => color,
+        },
+        tail_follows_color: json.get_key_value("tail_color").is_err(),
+    })
+}
+
+fn parse_color(node: &Node) -> Result<RGB8, CommandError> {
+    let value = node.get_key_value("color")?;
+    if value.get_key_value("palette").is_ok() {
+        // from a palette, filled in when the event is drawn
+        return Ok(RGB8 { r: 0, g: 0, b: 0 });
+    }
+    read_color(&value)
+}
+
+// A colour given as [r,g,b], a "#rrggbb" string, {"h":30,"s":1,"v":0.5} with the hue in degrees, or
+// a colour temperature {"kelvin":2700,"brightness":0.5}. Saturation, value and brightness default to 1.
+pub(crate) fn read_color(value: &JSONValue) -> Result<RGB8, CommandError> {
+    match value.value_type {
+        JSONValueType::String => {
+            return parse_hex(value.read_string()?).ok_or(CommandError::InvalidColor);
+        }
+        JSONValueType::Object => {
+            let unit = |key: &str| -> Result<f32, CommandError> {
+                match value.get_key_value(key) {
+                    Ok(fraction) => Ok(fraction.read_float()?.clamp(0.0, 1.0)),
+                    Err(_) => Ok(1.0),
+                }
+            };
+            if let Ok(kelvin) = value.get_key_value("kelvin") {
+                let color = kelvin_to_rgb(kelvin.read_float()?);
+                let brightness = unit("brightness")?;
+                let dim = |channel: u8| (channel as f32 * brightness).round() as u8;
+                return Ok(RGB8 {
+                    r: dim(color.r),
+                    g: dim(color.g),
+                    b: dim(color.b),
+                });
+            }
+            let hue = value.get_key_value("h")?.read_float()? / 360.0;
+            return Ok(hsv_to_rgb(hue, unit("s")?, unit("v")?));
+        }
+        _ => {}
+    }
+
+    let mut color: Vec<u8, 3> = Vec::new();
+    for channel in value.iter_array()? {
+        color
+            .push(channel.read_integer()? as u8)
+            .map_err(|_| CommandError::InvalidColor)?;
+    }
+
+    if color.len() != 3 {
+        return Err(CommandError::InvalidColor);
+    }
+    Ok(RGB8 {
+        r: color[0],
+        g: color[1],
+        b: color[2],
+    })
+}
+
+// A colour given as {"palette":"theme","index":2} or {"palette":"theme","position":0.5}, or a list
+// of them given as {"palette":"theme"}, in "color", "colors" or "stops"
+fn parse_palette_ref(node: &Node) -> Result<Option<PaletteRef>, CommandError> {
+    for key in ["color", "colors", "stops"] {
+        let value = match node.get_key_value(key) {
+            Ok(value) => value,
+            Err(_) => continue,
+        };
+        let name = match value.get_key_value("palette") {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+
+        let pick = if let Ok(index) = value.get_key_value("index") {
+            PalettePick::Index(index.read_integer()? as u8)
+        } else if let Ok(position) = value.get_key_value("position") {
+            let position = position.read_float()?;
+            PalettePick::Position((position.clamp(0.0, 1.0) * 255.0).round() as u8)
+        } else {
+            PalettePick::All
+        };
+        return Ok(Some(PaletteRef {
+            name: name_hash(name.read_string()?),
+            pick,
+        }));
+    }
+    Ok(None)
+}
+
+// "loop" is optional: a repeat count, "forever" or "pingpong"
+fn parse_loop_mode(node: &Node) -> Result<LoopMode, CommandError> {
+    let value = match node.get_key_value("loop") {
+        Ok(value) => value,
+        Err(_) => return Ok(LoopMode::Once),
+    };
+
+    Ok(match value.value_type {
+        JSONValueType::Number => LoopMode::Times(value.read_integer()? as u16),
+        JSONValueType::String => match value.read_string()? {
+            "forever" => LoopMode::Forever,
+            "pingpong" => LoopMode::PingPong,
+            _ => LoopMode::Once,
+        },
+        _ => LoopMode::Once,
+    })
+}
+
+fn parse_id(node: &Node) -> Result<Option<u16>, CommandError> {
+    match node.get_key_value("id") {
+        Ok(id) => Ok(Some(id.read_integer()? as u16)),
+        Err(_) => Ok(None),
+    }
+}
+
+// microjson can tell a boolean apart but has no reader for it, so the token is taken from the
+// value's Debug output, which is only a few bytes for true and false
+pub(crate) fn read_bool(value: &JSONValue) -> Result<bool, CommandError> {
+    if value.value_type != JSONValueType::Bool {
+        return Err(CommandError::Json(JSONParsingError::UnexpectedToken));
+    }
+    let mut debug: String<64> = String::new();
+    let _ = write!(debug, "{:?}", value);
+    Ok(debug.contains("contents: \"true\""))
+}
+
+// A colour gradient over start_idx..end_idx:
+// {"type":"gradient","strip_idx":3,"start_idx":0,"end_idx":100,"duration":10,
+//  "stops":[[255,0,0],{"color":[0,0,255],"position":0.8}],"interpolation":"oklab",
+//  "scroll_speed":5,"fadein_duration":1,"fadeout_duration":1}
+// Stops given as a bare colour are spread evenly. Interpolation, scroll speed and fades are optional.
+fn process_gradient_node(
+    node: &Node,
+    now: Timestamp,
+    events: &mut Vec<EventWrapper, MAX_EVENTS>,
+    report: &mut PushReport,
+) -> Result<(), CommandError> {
+    let strip_idx = node.get_key_value("strip_idx")?.read_integer()? as usize;
+    if strip_idx != STRIP_INDICES.0 && strip_idx != STRIP_INDICES.1 {
+        return Ok(());
+    }
+
+    let (stops, stop_count) = parse_gradient_stops(node)?;
+    let interpolation = match node.get_key_value("interpolation") {
+        Ok(name) => {
+            Interpolation::from_name(name.read_string()?).ok_or(CommandError::InvalidGradient)?
+        }
+        Err(_) => Interpolation::Rgb,
+    };
+
+    let event = Event::Gradient(GradientEvent {
+        stops,
+        stop_count,
+        interpolation,
+        strip_idx,
+        pixels: PixelRange {
+            start: node.get_key_value("start_idx")?.read_integer()? as u16,
+            end: node.get_key_value("end_idx")?.read_integer()? as u16,
+        },
+        scroll_speed: parse_optional_float(node, "scroll_speed")?,
+        duration: node.get_key_value("duration")?.read_float()?,
+        fadein_duration: parse_optional_float(node, "fadein_duration")?,
+        fadeout_duration: parse_optional_float(node, "fadeout_duration")?,
+    });
+    push_event(events, new_wrapper(node, event, now)?, report);
+    Ok(())
+}
+
+fn parse_gradient_stops(
+    node: &Node,
+) -> Result<([GradientStop; MAX_GRADIENT_STOPS], u8), CommandError> {
+    let mut stops = [GradientStop { color: RGB8 { r: 0, g: 0, b: 0 }, position: 0 }; MAX_GRADIENT_STOPS];
+    let json_stops = node.get_key_value("stops")?;
+    if json_stops.value_type == JSONValueType::Object {
+        // the palette's colours, spread evenly when the event is drawn
+        json_stops.get_key_value("palette")?;
+        return Ok((stops, 1));
+    }
+
+    let count = json_stops.iter_array()?.count();
+    if count == 0 || count > MAX_GRADIENT_STOPS {
+        return Err(CommandError::InvalidGradient);
+    }
+
+    for (i, (stop, json_stop)) in stops.iter_mut().zip(json_stops.iter_array()?).enumerate() {
+        let even_position = if count == 1 { 0.0 } else { i as f32 / (count - 1) as f32 };
+        let position = match json_stop.value_type {
+            JSONValueType::Object if json_stop.get_key_value("color").is_ok() => {
+                stop.color = read_color(&json_stop.get_key_value("color")?)?;
+                match json_stop.get_key_value("position") {
+                    Ok(position) => position.read_float()?,
+                    Err(_) => even_position,
+                }
+            }
+            _ => {
+                stop.color = read_color(&json_stop)?;
+                even_position
+            }
+        };
+        stop.position = (position.clamp(0.0, 1.0) * 255.0).round() as u8;
+    }
+    Ok((stops, count as u8))
+}
+
+// Random pixels of start_idx..end_idx light up and fade out again:
+// {"type":"sparkle","strip_idx":3,"start_idx":0,"end_idx":100,"duration":30,"density":0.1,
+//  "colors":[[255,255,255],[255,180,80]],"attack":0.1,"decay":0.6,"seed":42}
+// A single "color" can be given instead of "colors", the seed defaults to 0.
+fn process_sparkle_node(
+    node: &Node,
+    now: Timestamp,
+    events: &mut Vec<EventWrapper, MAX_EVENTS>,
+    report: &mut PushReport,
+) -> Result<(), CommandError> {
+    let strip_idx = node.get_key_value("strip_idx")?.read_integer()? as usize;
+    if strip_idx != STRIP_INDICES.0 && strip_idx != STRIP_INDICES.1 {
+        return Ok(());
+    }
+
+    let (colors, color_count) = parse_colors::<MAX_COLORS>(node)?;
+    let event = Event::Sparkle(SparkleEvent {
+        colors,
+        color_count,
+        density: node.get_key_value("density")?.read_float()?,
+        attack: node.get_key_value("attack")?.read_float()?,
+        decay: node.get_key_value("decay")?.read_float()?,
+        duration: node.get_key_value("duration")?.read_float()?,
+        seed: match node.get_key_value("seed") {
+            Ok(seed) => seed.read_integer()? as u32,
+            Err(_) => 0,
+        },
+        strip_idx,
+        pixels: PixelRange {
+            start: node.get_key_value("start_idx")?.read_integer()? as u16,
+            end: node.get_key_value("end_idx")?.read_integer()? as u16,
+        },
+    });
+    push_event(events, new_wrapper(node, event, now)?, report);
+    Ok(())
+}
+
+// Rings spreading both ways from center_idx:
+// {"type":"ripple","strip_idx":3,"center_idx":60,"color":[0,120,255],"speed":40,"width":6,
+//  "decay":0.03,"rings":3,"ring_interval":0.4,"radius":50}
+// Decay, rings (1), ring_interval (0.5s) and radius (up to the far end of the strip) are optional.
+fn process_ripple_node(
+    node: &Node,
+    now: Timestamp,
+    events: &mut Vec<EventWrapper, MAX_EVENTS>,
+    report: &mut PushReport,
+) -> Result<(), CommandError> {
+    let strip_idx = node.get_key_value("strip_idx")?.read_integer()? as usize;
+    if strip_idx != STRIP_INDICES.0 && strip_idx != STRIP_INDICES.1 {
+        return Ok(());
+    }
+
+    let center_idx = node.get_key_value("center_idx")?.read_integer()? as u16;
+    let furthest_end = (center_idx as usize).max(STRIP_LENGTH.saturating_sub(center_idx as usize + 1));
+    let event = Event::Ripple(RippleEvent {
+        color: parse_color(node)?,
+        center_idx,
+        speed: parse_speed(node)?,
+        width: node.get_key_value("width")?.read_integer()? as u16,
+        radius: match node.get_key_value("radius") {
+            Ok(radius) => radius.read_integer()? as u16,
+            Err(_) => furthest_end as u16,
+        },
+        decay: parse_optional_float(node, "decay")?,
+        rings: match node.get_key_value("rings") {
+            Ok(rings) => rings.read_integer()? as u8,
+            Err(_) => 1,
+        },
+        ring_interval: match node.get_key_value("ring_interval") {
+            Ok(ring_interval) => ring_interval.read_float()?,
+            Err(_) => 0.5,
+        },
+        strip_idx,
+    });
+    push_event(events, new_wrapper(node, event, now)?, report);
+    Ok(())
+}
+
+// Flowing noise over start_idx..end_idx, coloured by blending along the colour list:
+// {"type":"noise","strip_idx":3,"start_idx":0,"end_idx":200,"duration":60,
+//  "colors":[[0,20,0],[40,160,20],[200,220,60]],"scale":0.08,"speed":0.3,"brightness":0.6,"seed":3}
+// Brightness defaults to 1 and the seed to 0.
+fn process_noise_node(
+    node: &Node,
+    now: Timestamp,
+    events: &mut Vec<EventWrapper, MAX_EVENTS>,
+    report: &mut PushReport,
+) -> Result<(), CommandError> {
+    let strip_idx = node.get_key_value("strip_idx")?.read_integer()? as usize;
+    if strip_idx != STRIP_INDICES.0 && strip_idx != STRIP_INDICES.1 {
+        return Ok(());
+    }
+
+    let (colors, color_count) = parse_colors::<MAX_COLORS>(node)?;
+    let event = Event::Noise(NoiseEvent {
+        colors,
+        color_count,
+        scale: node.get_key_value("scale")?.read_float()?,
+        speed: node.get_key_value("speed")?.read_float()?,
+        brightness: match node.get_key_value("brightness") {
+            Ok(brightness) => brightness.read_float()?,
+            Err(_) => 1.0,
+        },
+        duration: node.get_key_value("duration")?.read_float()?,
+        seed: match node.get_key_value("seed") {
+            Ok(seed) => seed.read_integer()? as u32,
+            Err(_) => 0,
+        },
+        strip_idx,
+        pixels: PixelRange {
+            start: node.get_key_value("start_idx")?.read_integer()? as u16,
+            end: node.get_key_value("end_idx")?.read_integer()? as u16,
+        },
+    });
+    push_event(events, new_wrapper(node, event, now)?, report);
+    Ok(())
+}
+
+// A network growing out from the first stretch of the path and branching off onto the others:
+// {"type":"grow","color":[120,255,60],"speed":15,"smoothing":3,"hold":10,"wither":4,
+//  "path":[{"strip_idx":3,"start_idx":40,"end_idx":120},{"strip_idx":1,"start_idx":0,"end_idx":60},
+//          {"strip_idx":3,"start_idx":40,"end_idx":0,"from":0,"at":40}]}
+// A branch starts growing once the front reaches pixel "at" of stretch "from", by default the end of
+// the stretch before it, so the front carries on across strip junctions. Every board works out the
+// whole network, so branches on other boards' strips stay in step. Smoothing, hold and wither are
+// optional.
+fn process_grow_node(
+    node: &Node,
+    now: Timestamp,
+    events: &mut Vec<EventWrapper, MAX_EVENTS>,
+    report: &mut PushReport,
+) -> Result<(), CommandError> {
+    let path = node.get_key_value("path")?;
+    // start, end and delay of every branch
+    let mut branches: Vec<(f32, f32, f32), MAX_GROW_BRANCHES> = Vec::new();
+    let mut reach: f32 = 0.0;
+    for (i, branch) in path.iter_array()?.enumerate() {
+        let start = branch.get_key_value("start_idx")?.read_integer()? as f32;
+        let end = branch.get_key_value("end_idx")?.read_integer()? as f32;
+        let delay = if i == 0 {
+            0.0
+        } else {
+            let from = match branch.get_key_value("from") {
+                Ok(from) => from.read_integer()? as usize,
+                Err(_) => i - 1,
+            };
+            let (parent_start, parent_end, parent_delay) =
+                *branches.get(from).ok_or(CommandError::InvalidPath)?;
+            let at = match branch.get_key_value("at") {
+                Ok(at) => at.read_integer()? as f32,
+                Err(_) => parent_end,
+            };
+            parent_delay + (at - parent_start).abs()
+        };
+        reach = reach.max(delay + (end - start).abs());
+        branches
+            .push((start, end, delay))
+            .map_err(|_| CommandError::InvalidPath)?;
+    }
+    if branches.is_empty() {
+        return Err(CommandError::InvalidPath);
+    }
+
+    let color = parse_color(node)?;
+    let speed = parse_speed(node)?;
+    let smoothing = parse_optional_float(node, "smoothing")?;
+    let hold = parse_optional_float(node, "hold")?;
+    let wither = parse_optional_float(node, "wither")?;
+    for (branch, (start, end, delay)) in path.iter_array()?.zip(branches) {
+        let strip_idx = branch.get_key_value("strip_idx")?.read_integer()? as usize;
+        if strip_idx != STRIP_INDICES.0 && strip_idx != STRIP_INDICES.1 {
+            continue;
+        }
+
+        let event = Event::Grow(GrowEvent {
+            color,
+            strip_idx,
+            start_idx: start as u16,
+            end_idx: end as u16,
+            delay,
+            reach,
+            speed,
+            smoothing,
+            hold,
+            wither,
+        });
+        push_event(events, new_wrapper(node, event, now)?, report);
+    }
+    Ok(())
+}
+
+// The "colors" array, or just the one "color"
+fn parse_colors<const N: usize>(node: &Node) -> Result<([RGB8; N], u8), CommandError> {
+    let mut colors = [RGB8 { r: 0, g: 0, b: 0 }; N];
+    let json_colors = match node.get_key_value("colors") {
+        Ok(json_colors) => json_colors,
+        Err(_) => {
+            colors[0] = parse_color(node)?;
+            return Ok((colors, 1));
+        }
+    };
+
+    if json_colors.value_type == JSONValueType::Object {
+        json_colors.get_key_value("palette")?;
+        return Ok((colors, 1));
+    }
+
+    let mut count = 0;
+    for json_color in json_colors.iter_array()? {
+        if count == N {
+            return Err(CommandError::InvalidColor);
+        }
+        colors[count] = read_color(&json_color)?;
+        count += 1;
+    }
+    if count == 0 {
+        return Err(CommandError::InvalidColor);
+    }
+    Ok((colors, count as u8))
+}
+
+// for events which travel along the strip, a speed of 0 would never get anywhere
+fn parse_speed(node: &Node) -> Result<f32, CommandError> {
+    let speed = node.get_key_value("speed")?.read_float()?;
+    if speed <= 0.0 {
+        return Err(CommandError::InvalidSpeed);
+    }
+    Ok(speed)
+}
+
+fn parse_optional_float(node: &Node, key: &str) -> Result<f32, CommandError> {
+    match node.get_key_value(key) {
+        Ok(value) => Ok(value.read_float()?),
+        Err(_) => Ok(0.0),
+    }
+}
+
+// Wraps an event which starts right away, with the timing keys every event type understands
+fn new_wrapper(node: &Node, event: Event, now: Timestamp) -> Result<EventWrapper, CommandError> {
+    let time_base = parse_time_base(node)?;
+    Ok(EventWrapper {
+        event,
+        start_time: Some(now.in_base(time_base)),
+        loop_mode: parse_loop_mode(node)?,
+        id: parse_id(node)?,
+        time_base,
+        time_scale: parse_time_scale(node)?,
+        idle: node.idle,
+        priority: parse_priority(node)?,
+        palette: parse_palette_ref(node)?,
+        hue_rotation: parse_hue_rotation(node)?,
+    })
+}
+
+// The "pixels" array lists single pixels, {"strip_idx":3,"pixel_idx":5}, or ranges of them,
+// {"strip_idx":3,"start_idx":0,"end_idx":40}, with end_idx itself not included. Every range becomes
+// one event, and runs of single pixels are merged into ranges so they take up one event as well.
+// Pixels on the other boards' strips are skipped. A "strip_idx" next to "pixels" moves all of them
+// to that strip, so a preset's pixels can be reused on another strip.
+fn for_each_pixel_range(
+    node: &Node,
+    mut add: impl FnMut(usize, PixelRange),
+) -> Result<(), CommandError> {
+    let strip_override = match node.get_key_value("strip_idx") {
+        Ok(strip_idx) => Some(strip_idx.read_integer()? as usize),
+        Err(_) => None,
+    };
+    let mut pending: Option<(usize, PixelRange)> = None;
+    for pixel in node.get_key_value("pixels")?.iter_array()? {
+        let strip_idx = match strip_override {
+            Some(strip_idx) => strip_idx,
+            None => pixel.get_key_value("strip_idx")?.read_integer()? as usize,
+        };
+        if strip_idx != STRIP_INDICES.0 && strip_idx != STRIP_INDICES.1 {
+            continue;
+        }
+        let range = match pixel.get_key_value("pixel_idx") {
+            Ok(pixel_idx) => PixelRange::single(pixel_idx.read_integer()? as u16),
+            Err(_) => PixelRange {
+                start: pixel.get_key_value("start_idx")?.read_integer()? as u16,
+                end: pixel.get_key_value("end_idx")?.read_integer()? as u16,
+            },
+        };
+
+        match &mut pending {
+            Some((pending_strip, pending_range))
+                if *pending_strip == strip_idx && pending_range.end == range.start =>
+            {
+                pending_range.end = range.end;
+            }
+            _ => {
+                if let Some((strip_idx, range)) = pending.replace((strip_idx, range)) {
+                    add(strip_idx, range);
+                }
+            }
+        }
+    }
+    if let Some((strip_idx, range)) = pending {
+        add(strip_idx, range);
+    }
+    Ok(())
+}
+
+// with "tempo": true the event's durations and paces are counted in beats of the global tempo
+fn parse_time_base(node: &Node) -> Result<TimeBase, CommandError> {
+    match node.get_key_value("tempo") {
+        Ok(tempo) if read_bool(&tempo)? => Ok(TimeBase::Beats),
+        _ => Ok(TimeBase::Seconds),
+    }
+}
+
+// events with a higher priority evict those with a lower one when the queue is full, priorities
+// outside 0..=255 are clamped
+fn parse_priority(node: &Node) -> Result<u8, CommandError> {
+    if node.idle {
+        return Ok(0);
+    }
+    match node.get_key_value("priority") {
+        Ok(priority) => Ok(priority.read_integer()?.clamp(0, u8::MAX as isize) as u8),
+        Err(_) => Ok(DEFAULT_PRIORITY),
+    }
+}
+
+// "hue_rotation" cycles the event's colours around the colour wheel, in degrees per second (or per
+// beat with "tempo"), on top of whatever the event does itself
+fn parse_hue_rotation(node: &Node) -> Result<f32, CommandError> {
+    Ok(parse_optional_float(node, "hue_rotation")? / 360.0)
+}
+
+fn parse_time_scale(node: &Node) -> Result<f32, CommandError> {
+    match node.get_key_value("time_scale") {
+        Ok(time_scale) => Ok(time_scale.read_float()?),
+        Err(_) => Ok(1.0),
     }
 }
 
 fn process_constant_node(
-    node: &JSONValue,
-    timer_seconds: f32,
+    node: &Node,
+    now: Timestamp,
     events: &mut Vec<EventWrapper, MAX_EVENTS>,
-    first_node: bool,
-) {
+    report: &mut PushReport,
+) -> Result<(), CommandError> {
     // constant events never have a next
-    if node.value_type == JSONValueType::Null {
-        return;
+    if node.json.value_type == JSONValueType::Null {
+        return Ok(());
     }
 
-    let color: Vec<u8, 3> = node
-        .get_key_value("color")
-        .unwrap()
-        .iter_array()
-        .unwrap()
-        .map(|x| x.read_integer().unwrap() as u8)
-        .collect();
+    let color = parse_color(node)?;
 
     let duration: f32 = node
-        .get_key_value("duration")
-        .unwrap()
-        .read_float()
-        .unwrap() as f32;
+        .get_key_value("duration")?
+        .read_float()?;
     let fadein_duration: u32 = node
-        .get_key_value("fadein_duration")
-        .unwrap()
-        .read_integer()
-        .unwrap() as u32;
+        .get_key_value("fadein_duration")?
+        .read_integer()? as u32;
     let fadeout_duration: u32 = node
-        .get_key_value("fadeout_duration")
-        .unwrap()
-        .read_integer()
-        .unwrap() as u32;
-
-    // loop over the pixels array of the json
-    node.get_key_value("pixels")
-        .unwrap()
-        .iter_array()
-        .unwrap()
-        .for_each(|pixel| {
-            let pixel_idx: usize = pixel
-                .get_key_value("pixel_idx")
-                .unwrap()
-                .read_integer()
-                .unwrap() as usize;
-            let strip_idx: usize = pixel
-                .get_key_value("strip_idx")
-                .unwrap()
-                .read_integer()
-                .unwrap() as usize;
-
-            if strip_idx == STRIP_INDICES.0 || strip_idx == STRIP_INDICES.1 {
-                events.push(EventWrapper {
-                    start_time: Some(timer_seconds),
-                    event: Event::Constant(ConstantEvent {
-                        color: RGB8 {
-                            r: color[0],
-                            g: color[1],
-                            b: color[2],
-                        },
-                        duration,
-                        fadein_duration,
-                        fadeout_duration,
-                        fade_power: 0,
-                        pixel_idx,
-                        strip_idx,
-                    }),
-                });
-            }
-        })
+        .get_key_value("fadeout_duration")?
+        .read_integer()? as u32;
+    let loop_mode = parse_loop_mode(node)?;
+    let id = parse_id(node)?;
+    let time_base = parse_time_base(node)?;
+    let time_scale = parse_time_scale(node)?;
+    let priority = parse_priority(node)?;
+    let palette = parse_palette_ref(node)?;
+    let hue_rotation = parse_hue_rotation(node)?;
+
+    for_each_pixel_range(node, |strip_idx, pixels| {
+        push_event(events, EventWrapper {
+            start_time: Some(now.in_base(time_base)),
+            event: Event::Constant(ConstantEvent {
+                color,
+                duration,
+                fadein_duration,
+                fadeout_duration,
+                fade_power: 0,
+                pixels,
+                strip_idx,
+            }),
+            loop_mode,
+            id,
+            time_base,
+            time_scale,
+            idle: node.idle,
+            priority,
+            palette,
+            hue_rotation,
+        }, report);
+    })
 }
 
 fn process_heartbeat_node(
-    node: &JSONValue,
-    timer_seconds: f32,
+    node: &Node,
+    now: Timestamp,
     events: &mut Vec<EventWrapper, MAX_EVENTS>,
-    first_node: bool,
-    ) {
+    report: &mut PushReport,
+) -> Result<(), CommandError> {
     // constant events never have a next
-    if node.value_type == JSONValueType::Null {
-        return;
+    if node.json.value_type == JSONValueType::Null {
+        return Ok(());
     }
 
-    let color: Vec<u8, 3> = node
-        .get_key_value("color")
-        .unwrap()
-        .iter_array()
-        .unwrap()
-        .map(|x| x.read_integer().unwrap() as u8)
-        .collect();
+    let color = parse_color(node)?;
 
     let duration: f32 = node
-        .get_key_value("duration")
-        .unwrap()
-        .read_float()
-        .unwrap() as f32;
+        .get_key_value("duration")?
+        .read_float()?;
     let first_pulse_attack: f32 = node
-        .get_key_value("first_pulse_attack")
-        .unwrap()
-        .read_float()
-        .unwrap() as f32;
+        .get_key_value("first_pulse_attack")?
+        .read_float()?;
     let first_pulse_decay: f32 = node
-        .get_key_value("first_pulse_decay")
-        .unwrap()
-        .read_float()
-        .unwrap() as f32;
+        .get_key_value("first_pulse_decay")?
+        .read_float()?;
     let second_pulse_attack: f32 = node
-        .get_key_value("second_pulse_attack")
-        .unwrap()
-        .read_float()
-        .unwrap() as f32;
+        .get_key_value("second_pulse_attack")?
+        .read_float()?;
     let second_pulse_decay: f32 = node
-        .get_key_value("second_pulse_decay")
-        .unwrap()
-        .read_float()
-        .unwrap() as f32;
+        .get_key_value("second_pulse_decay")?
+        .read_float()?;
     let loop_duration: f32 = node
-        .get_key_value("loop_duration")
-        .unwrap()
-        .read_float()
-        .unwrap() as f32;
+        .get_key_value("loop_duration")?
+        .read_float()?;
     let dimness: f32 = node
-        .get_key_value("dimness")
-        .unwrap()
-        .read_float()
-        .unwrap() as f32;
-
-    // loop over the pixels array of the json
-    node.get_key_value("pixels")
-        .unwrap()
-        .iter_array()
-        .unwrap()
-        .for_each(|pixel| {
-            let pixel_idx: usize = pixel
-                .get_key_value("pixel_idx")
-                .unwrap()
-                .read_integer()
-                .unwrap() as usize;
-            let strip_idx: usize = pixel
-                .get_key_value("strip_idx")
-                .unwrap()
-                .read_integer()
-                .unwrap() as usize;
-
-            if strip_idx == STRIP_INDICES.0 || strip_idx == STRIP_INDICES.1 {
-                events.push(EventWrapper {
-                    start_time: Some(timer_seconds),
-                    event: Event::Heartbeat(HeartbeatEvent {
-                        color: RGB8 {
-                            r: color[0],
-                            g: color[1],
-                            b: color[2],
-                        },
-                        duration,
-                        first_pulse_attack,
-                        first_pulse_decay,
-                        second_pulse_attack,
-                        second_pulse_decay,
-                        loop_duration,
-                        dimness,
-                        pixel_idx,
-                        strip_idx,
-                    }),
-                });
+        .get_key_value("dimness")?
+        .read_float()?;
+    let loop_mode = parse_loop_mode(node)?;
+    let id = parse_id(node)?;
+    let time_base = parse_time_base(node)?;
+    let time_scale = parse_time_scale(node)?;
+    let priority = parse_priority(node)?;
+    let palette = parse_palette_ref(node)?;
+    let hue_rotation = parse_hue_rotation(node)?;
+
+    for_each_pixel_range(node, |strip_idx, pixels| {
+        push_event(events, EventWrapper {
+            start_time: Some(now.in_base(time_base)),
+            event: Event::Heartbeat(HeartbeatEvent {
+                color,
+                duration,
+                first_pulse_attack,
+                first_pulse_decay,
+                second_pulse_attack,
+                second_pulse_decay,
+                loop_duration,
+                dimness,
+                pixels,
+                strip_idx,
+            }),
+            loop_mode,
+            id,
+            time_base,
+            time_scale,
+            idle: node.idle,
+            priority,
+            palette,
+            hue_rotation,
+        }, report);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        storage::{Slot, SLOT_SIZE},
+        structs::Duration,
+    };
+
+    fn node(json: &str) -> Node<'_> {
+        Node::new(JSONValue::parse(json).unwrap())
+    }
+
+    struct RamStorage {
+        slots: [Vec<u8, SLOT_SIZE>; 3],
+    }
+
+    impl Storage for RamStorage {
+        fn load(&self, slot: Slot) -> Option<&[u8]> {
+            let data = &self.slots[slot as usize];
+            if data.is_empty() {
+                None
+            } else {
+                Some(data)
             }
+        }
+
+        fn store(&mut self, slot: Slot, data: &[u8]) -> Result<(), StorageError> {
+            self.slots[slot as usize] = Vec::from_slice(data).map_err(|_| StorageError::TooLarge)?;
+            Ok(())
+        }
+    }
+
+    // Everything a command can touch, starting out the way the board boots
+    struct Board {
+        events: Vec<EventWrapper, MAX_EVENTS>,
+        clock: VirtualClock,
+        telemetry: Telemetry,
+        crash_record: CrashRecord,
+        idle_scene: IdleScene,
+        governor: FrameGovernor,
+        palettes: Palettes,
+        stream: StreamLayer,
+        storage: RamStorage,
+        slot_buffer: SlotBuffer,
+    }
+
+    impl Board {
+        fn new() -> Self {
+            Board {
+                events: Vec::new(),
+                clock: VirtualClock::new(),
+                telemetry: Telemetry::new(),
+                crash_record: CrashRecord::empty(),
+                idle_scene: IdleScene::new(),
+                governor: FrameGovernor::new(),
+                palettes: Palettes::new(),
+                stream: StreamLayer::new(),
+                storage: RamStorage {
+                    slots: [Vec::new(), Vec::new(), Vec::new()],
+                },
+                slot_buffer: Vec::new(),
+            }
+        }
+
+        fn run(&mut self, json: &str) -> Option<Response> {
+            let now = self.clock.now();
+            let mut context = CommandContext {
+                clock: &mut self.clock,
+                telemetry: &mut self.telemetry,
+                crash_record: &mut self.crash_record,
+                idle_scene: &mut self.idle_scene,
+                governor: &mut self.governor,
+                palettes: &mut self.palettes,
+                stream: &mut self.stream,
+                storage: &mut self.storage,
+                slot_buffer: &mut self.slot_buffer,
+            };
+            add_events_from_json(&mut self.events, &mut context, json, now, now.seconds)
+        }
+    }
+
+    #[test]
+    fn tempo_counts_in_beats() {
+        assert!(matches!(parse_time_base(&node("{\"tempo\":true}")), Ok(TimeBase::Beats)));
+        assert!(matches!(parse_time_base(&node("{\"tempo\": false}")), Ok(TimeBase::Seconds)));
+        assert!(matches!(parse_time_base(&node("{\"type\":\"constant\"}")), Ok(TimeBase::Seconds)));
+        assert!(parse_time_base(&node("{\"tempo\":1}")).is_err());
+    }
+
+    #[test]
+    fn presets_are_stored_and_moved_to_other_strips() {
+        let mut board = Board::new();
+        board.run(concat!(
+            r#"{"type":"define_preset","name":"alarm","preset":{"type":"heartbeat","color":[255,0,0],"#,
+            r#""duration":1,"first_pulse_attack":0.1,"first_pulse_decay":0.1,"second_pulse_attack":0.1,"#,
+            r#""second_pulse_decay":0.1,"loop_duration":1,"dimness":0.5,"#,
+            r#""pixels":[{"strip_idx":3,"pixel_idx":5},{"strip_idx":1,"pixel_idx":20}]}}"#,
+        ));
+        assert!(board.run(r#"{"type":"preset","name":"missing"}"#).is_some());
+        assert_eq!(board.telemetry.parse_errors, 1);
+
+        board.run(r#"{"type":"preset","name":"alarm"}"#);
+        let strips: Vec<usize, 4> = board
+            .events
+            .iter()
+            .map(|event| match &event.event {
+                Event::Heartbeat(e) => e.strip_idx,
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(&strips[..], &[3, 1]);
+
+        board.events.clear();
+        board.run(r#"{"type":"preset","name":"alarm","strip_idx":1,"color":[0,0,255]}"#);
+        assert_eq!(board.events.len(), 2);
+        for event in board.events.iter() {
+            match &event.event {
+                Event::Heartbeat(e) => assert_eq!((e.strip_idx, e.color), (1, RGB8 { r: 0, g: 0, b: 255 })),
+                _ => unreachable!(),
+            }
+        }
+
+        board.run(r#"{"type":"delete_preset","name":"alarm"}"#);
+        assert!(board.run(r#"{"type":"preset","name":"alarm"}"#).is_some());
+        assert_eq!(board.telemetry.parse_errors, 2);
+    }
+
+    const CONSTANT: &str = concat!(
+        r#""type":"constant","color":[255,0,0],"duration":10,"fadein_duration":0,"#,
+        r#""fadeout_duration":0,"pixels":[{"strip_idx":3,"pixel_idx":0}]"#,
+    );
+
+    // a constant event with the given keys, which take precedence over the defaults
+    fn constant(keys: &str) -> String<256> {
+        let mut json = String::new();
+        let _ = write!(json, "{{{},{}}}", keys, CONSTANT);
+        json
+    }
+
+    #[test]
+    fn full_queue_evicts_the_oldest_lowest_priority_event() {
+        let mut board = Board::new();
+        while !board.events.is_full() {
+            board.run(&constant(r#""priority":10"#));
+        }
+        for (idx, event) in board.events.iter_mut().enumerate() {
+            event.id = Some(idx as u16);
+        }
+        board.events[3].priority = 1;
+        board.events[7].priority = 1;
+
+        let response = board.run(&constant(r#""priority":5"#)).unwrap();
+        assert_eq!(response, "{\"type\":\"evicted\",\"evicted\":1,\"dropped\":0}\n");
+        assert!(board.events.iter().all(|event| event.id != Some(3)));
+        assert_eq!(board.events.iter().filter(|event| event.priority == 1).count(), 1);
+        assert_eq!(board.events[MAX_EVENTS - 1].priority, 5);
+
+        board.run(&constant(r#""priority":5"#));
+        assert!(board.events.iter().all(|event| event.id != Some(7)));
+        let response = board.run(&constant(r#""priority":4"#)).unwrap();
+        assert_eq!(response, "{\"type\":\"evicted\",\"evicted\":0,\"dropped\":1}\n");
+        assert_eq!((board.telemetry.evicted, board.telemetry.dropped), (2, 1));
+
+        board.run(&constant(r#""priority":300"#));
+        assert_eq!(board.events[MAX_EVENTS - 1].priority, 255);
+    }
+
+    #[test]
+    fn evicting_a_chain_evicts_all_of_it() {
+        const MESSAGE: &str = concat!(
+            r#""color":[0,255,0],"pace":10,"message_width":3,"strip_idx":3,"start_idx":0,"#,
+            r#""end_idx":50,"priority":1"#,
+        );
+        let mut board = Board::new();
+        while board.events.len() < MAX_EVENTS - 3 {
+            board.run(&constant(r#""priority":10"#));
+        }
+        let mut chain: String<512> = String::new();
+        let _ = write!(chain, r#"{{"type":"message",{0},"next":{{{0},"next":{{{0}}}}}}}"#, MESSAGE);
+        board.run(&chain);
+        assert!(board.events.is_full());
+        assert_eq!(board.events.iter().filter(|event| !event.active()).count(), 2);
+
+        let response = board.run(&constant(r#""priority":5"#)).unwrap();
+        assert_eq!(response, "{\"type\":\"evicted\",\"evicted\":3,\"dropped\":0}\n");
+        assert!(board.events.iter().all(|event| event.active() && event.priority != 1));
+        assert_eq!(board.events.len(), MAX_EVENTS - 2);
+    }
+
+    fn pixel_ranges(json: &str) -> Vec<(usize, PixelRange), 8> {
+        let mut ranges = Vec::new();
+        for_each_pixel_range(&node(json), |strip_idx, range| {
+            let _ = ranges.push((strip_idx, range));
         })
-}
+        .unwrap();
+        ranges
+    }
+
+    #[test]
+    fn pixel_lists_are_merged_into_ranges() {
+        // single pixels, as older hosts send them
+        let ranges = pixel_ranges(concat!(
+            r#"{"pixels":[{"strip_idx":3,"pixel_idx":4},{"strip_idx":3,"pixel_idx":5},"#,
+            r#"{"strip_idx":3,"pixel_idx":6},{"strip_idx":3,"pixel_idx":9},"#,
+            r#"{"strip_idx":7,"pixel_idx":10},{"strip_idx":1,"pixel_idx":10}]}"#,
+        ));
+        assert_eq!(
+            &ranges[..],
+            &[
+                (3, PixelRange { start: 4, end: 7 }),
+                (3, PixelRange::single(9)),
+                (1, PixelRange::single(10)),
+            ]
+        );
+
+        // ranges, with pixels joining on where they end
+        let ranges = pixel_ranges(concat!(
+            r#"{"pixels":[{"strip_idx":1,"start_idx":0,"end_idx":50},{"strip_idx":1,"pixel_idx":50},"#,
+            r#"{"strip_idx":3,"start_idx":51,"end_idx":60}]}"#,
+        ));
+        assert_eq!(&ranges[..], &[(1, PixelRange { start: 0, end: 51 }), (3, PixelRange { start: 51, end: 60 })]);
+    }
 
+    #[test]
+    fn ripples_need_a_speed() {
+        let ripple = r#"{"type":"ripple","strip_idx":3,"center_idx":60,"color":[0,0,255],"width":6,"speed":"#;
+        let mut board = Board::new();
+        for (speed, parse_errors) in [("40}", 0), ("0}", 1), ("-5}", 2)] {
+            let mut json: String<128> = String::new();
+            let _ = write!(json, "{}{}", ripple, speed);
+            board.run(&json);
+            assert_eq!(board.telemetry.parse_errors, parse_errors);
+        }
+        assert_eq!(board.events.len(), 1);
+    }
+
+    #[test]
+    fn stored_palettes_survive_a_reboot() {
+        let mut board = Board::new();
+        board.run(r#"{"type":"define_palette","name":"theme","colors":[[255,0,0],[0,255,0]],"store":true}"#);
+        board.run(r#"{"type":"define_palette","name":"scratch","colors":[[0,0,255]],"store":false}"#);
+        assert_eq!(board.telemetry.parse_errors, 0);
+        board.run(&constant(r#""color":{"palette":"theme","index":1}"#));
+        let reference = board.events[0].palette.unwrap();
+        assert_eq!(reference, PaletteRef { name: name_hash("theme"), pick: PalettePick::Index(1) });
+
+        let mut rebooted = Palettes::new();
+        rebooted.load(&board.storage);
+        rebooted.recolor(reference, &mut board.events[0].event);
+        match &board.events[0].event {
+            Event::Constant(e) => assert_eq!(e.color, RGB8 { r: 0, g: 255, b: 0 }),
+            _ => unreachable!(),
+        }
+        let stored = core::str::from_utf8(board.storage.load(Slot::Palettes).unwrap()).unwrap();
+        assert!(stored.contains("theme") && !stored.contains("scratch"));
+
+        board.run(r#"{"type":"delete_palette","name":"theme"}"#);
+        assert_eq!(board.storage.load(Slot::Palettes), None);
+    }
+
+    #[test]
+    fn only_tails_without_their_own_colour_follow_the_palette() {
+        const MESSAGE: &str = concat!(
+            r#"{"type":"message","color":{"palette":"night","index":1},"pace":10,"message_width":3,"#,
+            r#""strip_idx":3,"start_idx":0,"end_idx":50,"tail_length":10"#,
+        );
+        let mut board = Board::new();
+        let night = RGB8 { r: 20, g: 0, b: 80 };
+        let mut json: String<256> = String::new();
+        let _ = write!(json, "{}}}", MESSAGE);
+        board.run(&json);
+        json.clear();
+        // a tail colour which happens to be the message's is still the tail's own
+        let _ = write!(json, r#"{},"tail_color":[20,0,80]}}"#, MESSAGE);
+        board.run(&json);
+        assert_eq!(board.telemetry.parse_errors, 0);
+
+        let recolor = |board: &mut Board| {
+            for event in board.events.iter_mut() {
+                board.palettes.recolor(event.palette.unwrap(), &mut event.event);
+            }
+        };
+        // drawn once with the original colours, so the second message's tail matches its head
+        recolor(&mut board);
+        board.run(r#"{"type":"define_palette","name":"night","colors":[[255,0,0],[0,255,0]]}"#);
+        recolor(&mut board);
+        let tails: Vec<RGB8, 2> = board
+            .events
+            .iter()
+            .map(|event| match &event.event {
+                Event::Message(e) => e.tail_color,
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(&tails[..], &[RGB8 { r: 0, g: 255, b: 0 }, night]);
+    }
+
+    #[test]
+    fn colors_in_every_notation() {
+        let color = |json: &str| read_color(&JSONValue::parse(json).unwrap());
+        assert_eq!(color("[1,2,3]").unwrap(), RGB8 { r: 1, g: 2, b: 3 });
+        assert_eq!(color("\"#ff8000\"").unwrap(), RGB8 { r: 255, g: 128, b: 0 });
+        assert!(color("\"#ff80\"").is_err());
+        assert_eq!(color(r#"{"h":240}"#).unwrap(), RGB8 { r: 0, g: 0, b: 255 });
+        assert_eq!(color(r#"{"h":120,"s":0,"v":2}"#).unwrap(), RGB8 { r: 255, g: 255, b: 255 });
+        assert_eq!(color(r#"{"kelvin":6500}"#).unwrap(), kelvin_to_rgb(6500.0));
+        let dimmed = color(r#"{"kelvin":2700,"brightness":0.5}"#).unwrap();
+        assert_eq!(dimmed.r, (kelvin_to_rgb(2700.0).r as f32 / 2.0).round() as u8);
+    }
+
+    #[test]
+    fn grow_needs_a_speed() {
+        let mut board = Board::new();
+        board.run(r#"{"type":"grow","color":[0,255,0],"speed":0,"path":[{"strip_idx":3,"start_idx":0,"end_idx":9}]}"#);
+        assert_eq!((board.telemetry.parse_errors, board.events.len()), (1, 0));
+        board.run(r#"{"type":"grow","color":[0,255,0],"speed":9,"path":[{"strip_idx":3,"start_idx":0,"end_idx":9}]}"#);
+        assert_eq!((board.telemetry.parse_errors, board.events.len()), (1, 1));
+    }
+}
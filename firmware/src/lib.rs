@@ -1,6 +1,18 @@
 #![no_std]
 pub mod structs;
-pub mod starting_events;
 pub mod json_events;
 pub mod new_strips;
 pub mod behaviours;
+pub mod color;
+pub mod random;
+pub mod fixed_behaviours;
+pub mod clock;
+pub mod telemetry;
+pub mod crash;
+pub mod storage;
+pub mod idle_scene;
+pub mod presets;
+pub mod palettes;
+pub mod governor;
+pub mod pipeline;
+pub mod stream;
@@ -1,7 +1,18 @@
 use crate::{
-    behaviours::{paint_message_event, paint_solid_pixel, paint_heartbeat_pixel},
-    structs::{Duration, EventWrapper},
+    clock::Timestamp,
+    color::rotate_hue,
+    palettes::Palettes,
+    stream::StreamLayer,
+    structs::{Duration, Event, EventWrapper},
 };
+use crate::behaviours::{
+    paint_gradient_event, paint_grow_event, paint_noise_event, paint_ripple_event,
+    paint_sparkle_event,
+};
+#[cfg(not(feature = "fixed-point"))]
+use crate::behaviours::{paint_heartbeat_pixel, paint_message_event, paint_solid_pixel};
+#[cfg(feature = "fixed-point")]
+use crate::fixed_behaviours::{paint_heartbeat_pixel, paint_message_event, paint_solid_pixel};
 use heapless::Vec;
 use micromath::F32Ext;
 use smart_leds_trait::RGB8;
@@ -19,83 +30,161 @@ pub const STRIP_INDICES: (usize, usize) = (3, 1);
 pub const SERIAL_NUM: &str = "IB_3_";
 pub const CLOCK_MULTIPLIER: f32 = 1.0 / 1024.0;
 pub const STRIP_LENGTH: usize = 200;
-pub const MAX_EVENTS: usize = 1028 * 3;
+// The event queue owns a fixed 160KB of the M4's 192KB, the 3084 events of 52 bytes it started
+// with. The rest holds the command buffer, the flash slot buffer, the frame buffers, the streamed
+// frames, the DMA output's two encoded strips of 1900 bytes and the stack, so the queue's share
+// stays put and a bigger EventWrapper costs queue slots rather than stack.
+pub const EVENT_RAM: usize = 3084 * 52;
+pub const MAX_EVENTS: usize = EVENT_RAM / core::mem::size_of::<EventWrapper>();
+
+// priority of events which don't set one, idle scene events always get 0
+pub const DEFAULT_PRIORITY: u8 = 100;
 
 #[derive(Copy, Clone)]
 pub struct Strips {
     pub strips: ([RGB8; STRIP_LENGTH], [RGB8; STRIP_LENGTH]),
 }
 
+impl Default for Strips {
+    fn default() -> Self {
+        Strips::new()
+    }
+}
+
+impl Strips {
+    pub const fn new() -> Self {
+        Strips {
+            strips: (
+                [RGB8 { r: 0, g: 0, b: 0 }; STRIP_LENGTH],
+                [RGB8 { r: 0, g: 0, b: 0 }; STRIP_LENGTH],
+            ),
+        }
+    }
+}
+
 pub fn calculate_new_strips(
-    timer_seconds: f32,
+    now: Timestamp,
+    real_seconds: f32,
     active_events: &mut Vec<EventWrapper, MAX_EVENTS>,
+    palettes: &Palettes,
+    stream: &mut StreamLayer,
 ) -> Strips {
-    update_events(timer_seconds, active_events);
+    let mut strips = Strips::new();
+    render_strips(now, real_seconds, active_events, palettes, stream, &mut strips);
+    strips
+}
 
-    let mut strips = Strips {
-        strips: (
-            [RGB8 { r: 0, g: 0, b: 0 }; STRIP_LENGTH],
-            [RGB8 { r: 0, g: 0, b: 0 }; STRIP_LENGTH],
-        ),
-    };
+// Paints the active events over a blank frame, reusing the caller's buffer. The stream is timed by
+// real_seconds, as it has to keep up with the host whatever the virtual clock is doing.
+pub fn render_strips(
+    now: Timestamp,
+    real_seconds: f32,
+    active_events: &mut Vec<EventWrapper, MAX_EVENTS>,
+    palettes: &Palettes,
+    stream: &mut StreamLayer,
+    strips: &mut Strips,
+) {
+    update_events(now, active_events);
+    *strips = Strips::new();
 
-    let mut events_iter = active_events.iter();
-    while let Some(event) = events_iter.next() {
+    for event in active_events.iter_mut() {
         if !event.active() {
             continue;
         }
 
-        match &event.event {
-            crate::structs::Event::Message(e) => {
-                if e.strip_idx == STRIP_INDICES.0 {
-                    paint_message_event(
-                        &mut strips.strips.0,
-                        e,
-                        event.start_time.unwrap(),
-                        timer_seconds,
-                    )
-                } else if e.strip_idx == STRIP_INDICES.1 {
-                    paint_message_event(
-                        &mut strips.strips.1,
-                        e,
-                        event.start_time.unwrap(),
-                        timer_seconds,
-                    )
-                }
-            },
-            crate::structs::Event::Constant(e) => {
-                if e.strip_idx == STRIP_INDICES.0 {
-                    paint_solid_pixel(&mut strips.strips.0, e, event.start_time.unwrap(), timer_seconds);
-                } else if e.strip_idx == STRIP_INDICES.1 {
-                    paint_solid_pixel(&mut strips.strips.1, e, event.start_time.unwrap(), timer_seconds);
-                }
-            },
-            crate::structs::Event::Heartbeat(e) => {
-                if e.strip_idx == STRIP_INDICES.0 {
-                    paint_heartbeat_pixel(&mut strips.strips.0, e, event.start_time.unwrap(), timer_seconds);
-                } else if e.strip_idx == STRIP_INDICES.1 {
-                    paint_heartbeat_pixel(&mut strips.strips.1, e, event.start_time.unwrap(), timer_seconds);
-                }
-            }
-            _ => {}
+        // events for the other boards' strips are skipped before any timing maths, the painters
+        // then only visit the pixels the event can light at this time
+        let strip = match event.event.strip_idx() {
+            idx if idx == STRIP_INDICES.0 => &mut strips.strips.0,
+            idx if idx == STRIP_INDICES.1 => &mut strips.strips.1,
+            _ => continue,
+        };
+        if let Some(reference) = event.palette {
+            palettes.recolor(reference, &mut event.event);
+        }
+        let elapsed = event.local_time(now);
+
+        if event.hue_rotation != 0.0 {
+            // rotated from the event's own colours every frame, so rounding never builds up
+            let turns = event.hue_rotation * event.elapsed(now);
+            let mut rotated = event.event.clone();
+            rotated.map_colors(|color| rotate_hue(color, turns));
+            paint_event(strip, &rotated, elapsed);
+        } else {
+            paint_event(strip, &event.event, elapsed);
         }
     }
 
-    strips
+    stream.composite(real_seconds, strips);
+}
+
+fn paint_event(strip: &mut [RGB8; STRIP_LENGTH], event: &Event, elapsed: f32) {
+    match event {
+        Event::Message(e) => paint_message_event(strip, e, elapsed),
+        Event::Constant(e) => paint_solid_pixel(strip, e, elapsed),
+        Event::Heartbeat(e) => paint_heartbeat_pixel(strip, e, elapsed),
+        Event::Gradient(e) => paint_gradient_event(strip, e, elapsed),
+        Event::Sparkle(e) => paint_sparkle_event(strip, e, elapsed),
+        Event::Ripple(e) => paint_ripple_event(strip, e, elapsed),
+        Event::Noise(e) => paint_noise_event(strip, e, elapsed),
+        Event::Grow(e) => paint_grow_event(strip, e, elapsed),
+    }
 }
 
-fn update_events(timer_seconds: f32, active_events: &mut Vec<EventWrapper, MAX_EVENTS>) -> () {
+fn update_events(now: Timestamp, active_events: &mut Vec<EventWrapper, MAX_EVENTS>) {
     // activate next events
     let mut mut_events_iter = active_events.iter_mut().peekable();
     while let Some(event) = mut_events_iter.next() {
-        if event.finished(timer_seconds) {
+        if event.finished(now) {
             if let Some(next_event) = mut_events_iter.peek_mut() {
                 if !next_event.active() {
-                    next_event.activate(timer_seconds);
+                    next_event.activate(now);
                 }
             }
         }
     }
 
-    active_events.retain(|event| !event.finished(timer_seconds));
+    active_events.retain(|event| !event.finished(now));
+}
+
+// What happened to the events pushed while running one command
+#[derive(Default)]
+pub struct PushReport {
+    pub evicted: u16,
+    pub dropped: u16,
+}
+
+// Adds an event, making room when the queue is full by evicting the event with the lowest priority,
+// the oldest one if there are several. If the new event has a lower priority than all of them, it
+// is dropped instead. The inactive events queued right behind the evicted one are the rest of its
+// "next" chain, which would never start without it, so they're evicted too.
+pub fn push_event(
+    active_events: &mut Vec<EventWrapper, MAX_EVENTS>,
+    event: EventWrapper,
+    report: &mut PushReport,
+) {
+    if !active_events.is_full() {
+        let _ = active_events.push(event);
+        return;
+    }
+
+    let victim = active_events
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, victim)| victim.priority)
+        .map(|(idx, victim)| (idx, victim.priority));
+    match victim {
+        Some((idx, priority)) if priority <= event.priority => {
+            let mut chain_end = idx + 1;
+            while chain_end < active_events.len() && !active_events[chain_end].active() {
+                chain_end += 1;
+            }
+            for _ in idx..chain_end {
+                active_events.remove(idx);
+            }
+            let _ = active_events.push(event);
+            report.evicted += (chain_end - idx) as u16;
+        }
+        _ => report.dropped += 1,
+    }
 }
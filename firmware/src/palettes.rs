@@ -0,0 +1,334 @@
+use crate::{
+    color::sample,
+    json_events::{read_bool, read_color, CommandError},
+    presets::{definition_is_named, store_definitions, stored_definitions},
+    storage::{Slot, SlotBuffer, Storage},
+    structs::{Event, GradientStop, MAX_GRADIENT_STOPS},
+};
+use heapless::Vec;
+use microjson::JSONValue;
+use smart_leds_trait::RGB8;
+
+pub const MAX_PALETTES: usize = 8;
+pub const MAX_PALETTE_COLORS: usize = 8;
+
+// Named colour schemes which events can take their colours from. Events only keep a reference to
+// the palette, their colours are looked up again every frame, so redefining a palette recolours
+// everything that uses it at once:
+// {"type":"define_palette","name":"theme","colors":[[255,120,0],[255,40,0],[80,0,0]],"store":true}
+// {"type":"constant","color":{"palette":"theme","index":1},...}
+// {"type":"ripple","color":{"palette":"theme","position":0.5},...}
+// {"type":"noise","colors":{"palette":"theme"},...}
+// A message's comet tail takes the palette colour as well, unless it has a "tail_color" of its own.
+// With "store" the definition is kept in flash, the same way as the presets. The compiled in
+// palettes can be redefined too, deleting them brings back the original colours.
+const BUILTIN_PALETTES: [(&str, &[RGB8]); 3] = [
+    (
+        "day",
+        &[
+            RGB8 { r: 255, g: 200, b: 120 },
+            RGB8 { r: 255, g: 255, b: 255 },
+            RGB8 { r: 120, g: 200, b: 255 },
+        ],
+    ),
+    (
+        "night",
+        &[
+            RGB8 { r: 0, g: 0, b: 40 },
+            RGB8 { r: 20, g: 0, b: 80 },
+            RGB8 { r: 0, g: 40, b: 60 },
+        ],
+    ),
+    (
+        "rainbow",
+        &[
+            RGB8 { r: 255, g: 0, b: 0 },
+            RGB8 { r: 255, g: 255, b: 0 },
+            RGB8 { r: 0, g: 255, b: 0 },
+            RGB8 { r: 0, g: 255, b: 255 },
+            RGB8 { r: 0, g: 0, b: 255 },
+            RGB8 { r: 255, g: 0, b: 255 },
+        ],
+    ),
+];
+
+// Events refer to palettes by a hash of the name, which keeps them small and lets an event name a
+// palette which is only defined later. With so few palettes 16 bits are plenty.
+pub fn name_hash(name: &str) -> u16 {
+    // 32 bit FNV-1a, folded in half
+    let hash = name
+        .bytes()
+        .fold(0x811c_9dc5, |hash: u32, byte| (hash ^ byte as u32).wrapping_mul(0x0100_0193));
+    (hash ^ (hash >> 16)) as u16
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PalettePick {
+    // one colour, wrapping around if the palette is shorter
+    Index(u8),
+    // blended between the colours, 0 is the first one and 255 the last
+    Position(u8),
+    // all of them, for the events which take a list of colours
+    All,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct PaletteRef {
+    pub name: u16,
+    pub pick: PalettePick,
+}
+
+struct Palette {
+    name: u16,
+    colors: Vec<RGB8, MAX_PALETTE_COLORS>,
+}
+
+pub struct Palettes {
+    palettes: Vec<Palette, MAX_PALETTES>,
+}
+
+impl Default for Palettes {
+    fn default() -> Self {
+        Palettes::new()
+    }
+}
+
+impl Palettes {
+    pub fn new() -> Self {
+        let mut palettes = Palettes {
+            palettes: Vec::new(),
+        };
+        for (name, colors) in BUILTIN_PALETTES.iter() {
+            let _ = palettes.set(name_hash(name), colors);
+        }
+        palettes
+    }
+
+    // Defines the palettes stored in flash, call once at boot
+    pub fn load(&mut self, storage: &dyn Storage) {
+        for definition in stored_definitions(storage, Slot::Palettes) {
+            if let Ok(json) = JSONValue::parse(definition) {
+                let _ = self.define(&json);
+            }
+        }
+    }
+
+    fn find(&self, name: u16) -> Option<&[RGB8]> {
+        self.palettes
+            .iter()
+            .find(|palette| palette.name == name)
+            .map(|palette| &palette.colors[..])
+    }
+
+    fn set(&mut self, name: u16, colors: &[RGB8]) -> Result<(), CommandError> {
+        let colors = Vec::from_slice(colors).map_err(|_| CommandError::InvalidPalette)?;
+        if colors.is_empty() {
+            return Err(CommandError::InvalidPalette);
+        }
+
+        match self.palettes.iter_mut().find(|palette| palette.name == name) {
+            Some(palette) => palette.colors = colors,
+            None => self
+                .palettes
+                .push(Palette { name, colors })
+                .map_err(|_| CommandError::InvalidPalette)?,
+        }
+        Ok(())
+    }
+
+    fn define(&mut self, json: &JSONValue) -> Result<(), CommandError> {
+        let name = json.get_key_value("name")?;
+        let mut colors: Vec<RGB8, MAX_PALETTE_COLORS> = Vec::new();
+        for json_color in json.get_key_value("colors")?.iter_array()? {
+            colors
+                .push(read_color(&json_color)?)
+                .map_err(|_| CommandError::InvalidPalette)?;
+        }
+        self.set(name_hash(name.read_string()?), &colors)
+    }
+
+    pub fn define_palette(
+        &mut self,
+        storage: &mut dyn Storage,
+        buffer: &mut SlotBuffer,
+        json: &JSONValue,
+        json_str: &str,
+    ) -> Result<(), CommandError> {
+        self.define(json)?;
+        if let Ok(store) = json.get_key_value("store") {
+            if read_bool(&store)? {
+                let name = json.get_key_value("name")?;
+                store_definitions(
+                    storage,
+                    buffer,
+                    Slot::Palettes,
+                    name.read_string()?,
+                    Some(json_str.trim_end()),
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    // Forgets the palette, in RAM and in flash. Compiled in palettes go back to their own colours.
+    pub fn delete_palette(
+        &mut self,
+        storage: &mut dyn Storage,
+        buffer: &mut SlotBuffer,
+        name: &str,
+    ) -> Result<(), CommandError> {
+        let stored = stored_definitions(storage, Slot::Palettes)
+            .any(|definition| definition_is_named(definition, name));
+        if stored {
+            store_definitions(storage, buffer, Slot::Palettes, name, None)?;
+        }
+
+        let hash = name_hash(name);
+        self.palettes.retain(|palette| palette.name != hash);
+        if let Some((_, colors)) = BUILTIN_PALETTES.iter().find(|(builtin, _)| *builtin == name) {
+            self.set(hash, colors)?;
+        }
+        Ok(())
+    }
+
+    // Updates the event's colours from the palette it refers to. Events naming a palette which
+    // doesn't exist keep the colours they had.
+    pub fn recolor(&self, reference: PaletteRef, event: &mut Event) {
+        let colors = match self.find(reference.name) {
+            Some(colors) => colors,
+            None => return,
+        };
+
+        let color = match reference.pick {
+            PalettePick::Index(idx) => colors[idx as usize % colors.len()],
+            PalettePick::Position(position) => sample(colors, position as f32 / 255.0),
+            PalettePick::All => {
+                match event {
+                    Event::Gradient(e) => {
+                        let (spread, count) = spread::<MAX_GRADIENT_STOPS>(colors);
+                        for (i, (stop, color)) in e.stops.iter_mut().zip(spread).take(count as usize).enumerate() {
+                            let position =
+                                if count < 2 { 0 } else { i * 255 / (count as usize - 1) };
+                            *stop = GradientStop {
+                                color,
+                                position: position as u8,
+                            };
+                        }
+                        e.stop_count = count;
+                    }
+                    Event::Sparkle(e) => {
+                        let (spread, count) = spread(colors);
+                        e.colors = spread;
+                        e.color_count = count;
+                    }
+                    Event::Noise(e) => {
+                        let (spread, count) = spread(colors);
+                        e.colors = spread;
+                        e.color_count = count;
+                    }
+                    _ => set_color(event, colors[0]),
+                }
+                return;
+            }
+        };
+        set_color(event, color);
+    }
+}
+
+// Palettes longer than what the event can hold are sampled evenly, so the first and last colours
+// are always kept
+fn spread<const N: usize>(colors: &[RGB8]) -> ([RGB8; N], u8) {
+    let mut spread = [RGB8 { r: 0, g: 0, b: 0 }; N];
+    if colors.len() <= N {
+        spread[..colors.len()].copy_from_slice(colors);
+        return (spread, colors.len() as u8);
+    }
+    for (i, color) in spread.iter_mut().enumerate() {
+        *color = sample(colors, i as f32 / (N - 1) as f32);
+    }
+    (spread, N as u8)
+}
+
+fn set_color(event: &mut Event, color: RGB8) {
+    match event {
+        Event::Message(e) => {
+            if e.tail_follows_color {
+                e.tail_color = color;
+            }
+            e.color = color;
+        }
+        Event::Constant(e) => e.color = color,
+        Event::Heartbeat(e) => e.color = color,
+        Event::Ripple(e) => e.color = color,
+        Event::Grow(e) => e.color = color,
+        Event::Gradient(e) => {
+            e.stops[0] = GradientStop { color, position: 0 };
+            e.stop_count = 1;
+        }
+        Event::Sparkle(e) => {
+            e.colors[0] = color;
+            e.color_count = 1;
+        }
+        Event::Noise(e) => {
+            e.colors[0] = color;
+            e.color_count = 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::{NoiseEvent, PixelRange, MAX_COLORS};
+
+    fn noise_event() -> Event {
+        Event::Noise(NoiseEvent {
+            colors: [RGB8 { r: 0, g: 0, b: 0 }; MAX_COLORS],
+            color_count: 1,
+            scale: 0.1,
+            speed: 0.1,
+            brightness: 1.0,
+            duration: 10.0,
+            seed: 0,
+            strip_idx: 0,
+            pixels: PixelRange { start: 0, end: 10 },
+        })
+    }
+
+    #[test]
+    fn redefining_a_palette_recolours_its_events() {
+        let mut palettes = Palettes::new();
+        let reference = PaletteRef {
+            name: name_hash("theme"),
+            pick: PalettePick::All,
+        };
+        let mut event = noise_event();
+
+        // not defined yet, the event keeps its colours
+        palettes.recolor(reference, &mut event);
+        let red = RGB8 { r: 255, g: 0, b: 0 };
+        palettes.set(name_hash("theme"), &[red, red, red]).unwrap();
+        palettes.recolor(reference, &mut event);
+        match &event {
+            Event::Noise(e) => assert_eq!((e.colors[2], e.color_count), (red, 3)),
+            _ => unreachable!(),
+        }
+
+        // longer than the event can hold: the ends are kept
+        palettes.recolor(
+            PaletteRef {
+                name: name_hash("rainbow"),
+                pick: PalettePick::All,
+            },
+            &mut event,
+        );
+        match &event {
+            Event::Noise(e) => {
+                assert_eq!(e.color_count as usize, MAX_COLORS);
+                assert_eq!(e.colors[0], red);
+                assert_eq!(e.colors[MAX_COLORS - 1], RGB8 { r: 255, g: 0, b: 255 });
+            }
+            _ => unreachable!(),
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use crate::{
+    clock::Timestamp,
+    new_strips::{render_strips, Strips, MAX_EVENTS},
+    palettes::Palettes,
+    stream::StreamLayer,
+    structs::EventWrapper,
+};
+use heapless::Vec;
+
+// Wherever finished frames go: the LED strips on the board, or a simulator when running on the host
+pub trait FrameSink {
+    // Starts sending the frame out. The sink is done reading the frame when this returns, but the
+    // strips may still be updating.
+    fn show(&mut self, frame: &Strips);
+    // Whether the last frame has been sent out completely
+    fn ready(&mut self) -> bool;
+}
+
+// Hands the frames from the compositor to the sink. One frame buffer is enough: the bit banging
+// sink has written the strips out before show() returns, and the DMA sink encodes the frame into
+// its own buffers, which act as the front buffer. Frame N+1 is rendered while frame N is still
+// being shifted out of those.
+pub struct FramePipeline {
+    frame: Strips,
+}
+
+impl Default for FramePipeline {
+    fn default() -> Self {
+        FramePipeline::new()
+    }
+}
+
+impl FramePipeline {
+    pub const fn new() -> Self {
+        FramePipeline {
+            frame: Strips::new(),
+        }
+    }
+
+    pub fn render(
+        &mut self,
+        now: Timestamp,
+        real_seconds: f32,
+        active_events: &mut Vec<EventWrapper, MAX_EVENTS>,
+        palettes: &Palettes,
+        stream: &mut StreamLayer,
+    ) {
+        render_strips(now, real_seconds, active_events, palettes, stream, &mut self.frame);
+    }
+
+    // Waits until the sink is done with the previous frame, then shows the newly rendered one
+    pub fn present(&mut self, sink: &mut dyn FrameSink) {
+        while !sink.ready() {}
+        sink.show(&self.frame);
+    }
+
+    // The frame which was rendered last
+    pub fn frame(&self) -> &Strips {
+        &self.frame
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock::TimeBase,
+        new_strips::{DEFAULT_PRIORITY, STRIP_INDICES},
+        structs::{ConstantEvent, Event, LoopMode, PixelRange},
+    };
+    use smart_leds_trait::RGB8;
+
+    // Stands in for the strips, remembering the first pixel of every frame it was shown
+    struct SimulatorSink {
+        shown: Vec<RGB8, 4>,
+    }
+
+    impl FrameSink for SimulatorSink {
+        fn show(&mut self, frame: &Strips) {
+            let _ = self.shown.push(frame.strips.0[0]);
+        }
+
+        fn ready(&mut self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn presents_every_rendered_frame() {
+        let mut events: Vec<EventWrapper, MAX_EVENTS> = Vec::new();
+        let _ = events.push(EventWrapper {
+            event: Event::Constant(ConstantEvent {
+                color: RGB8 { r: 10, g: 0, b: 0 },
+                duration: 1.0,
+                fadein_duration: 0,
+                fadeout_duration: 0,
+                fade_power: 1,
+                strip_idx: STRIP_INDICES.0,
+                pixels: PixelRange::single(0),
+            }),
+            start_time: Some(0.0),
+            loop_mode: LoopMode::Once,
+            id: None,
+            time_base: TimeBase::Seconds,
+            time_scale: 1.0,
+            idle: false,
+            priority: DEFAULT_PRIORITY,
+            palette: None,
+            hue_rotation: 0.0,
+        });
+        let mut pipeline = FramePipeline::new();
+        let mut sink = SimulatorSink { shown: Vec::new() };
+        let palettes = Palettes::new();
+        let mut stream = StreamLayer::new();
+
+        pipeline.render(Timestamp { seconds: 0.5, beats: 0.5 }, 0.5, &mut events, &palettes, &mut stream);
+        pipeline.present(&mut sink);
+        pipeline.render(Timestamp { seconds: 2.0, beats: 2.0 }, 2.0, &mut events, &palettes, &mut stream);
+        assert_eq!(pipeline.frame().strips.0[0], RGB8 { r: 0, g: 0, b: 0 });
+        pipeline.present(&mut sink);
+
+        assert_eq!(sink.shown, [RGB8 { r: 10, g: 0, b: 0 }, RGB8 { r: 0, g: 0, b: 0 }]);
+    }
+}
@@ -0,0 +1,92 @@
+use crate::{
+    json_events::CommandError,
+    storage::{Slot, SlotBuffer, Storage, StorageError},
+};
+use microjson::JSONValue;
+
+// Presets are event templates kept in flash, so the host only has to send what differs per call:
+// {"type":"define_preset","name":"alarm","preset":{"type":"heartbeat","color":[255,0,0],...}}
+// {"type":"preset","name":"alarm","strip_idx":3}
+// Keys in the call replace the preset's, a "strip_idx" moves all of the preset's pixels to that strip.
+// The definitions are stored as the command lines they were sent as, one per line. Palettes are
+// kept the same way in their own slot.
+pub(crate) fn stored_definitions(storage: &dyn Storage, slot: Slot) -> impl Iterator<Item = &str> {
+    storage
+        .load(slot)
+        .and_then(|definitions| core::str::from_utf8(definitions).ok())
+        .unwrap_or("")
+        .split('\n')
+        .filter(|definition| !definition.is_empty())
+}
+
+// Like get_key_value, but the value keeps the lifetime of the string it was parsed from
+fn find_key<'a>(json: &JSONValue<'a>, key: &str) -> Option<JSONValue<'a>> {
+    json.iter_object()
+        .ok()?
+        .find(|(item_key, _)| *item_key == key)
+        .map(|(_, value)| value)
+}
+
+// The name is read from a value local to this function, so it's compared here rather than returned
+pub(crate) fn definition_is_named(definition: &str, name: &str) -> bool {
+    let json = match JSONValue::parse(definition) {
+        Ok(json) => json,
+        Err(_) => return false,
+    };
+    match find_key(&json, "name") {
+        Some(value) => value.read_string() == Ok(name),
+        None => false,
+    }
+}
+
+// The template of the named preset, with the values to use for keys the call leaves out
+pub fn find_preset<'a>(storage: &'a dyn Storage, name: &str) -> Option<JSONValue<'a>> {
+    stored_definitions(storage, Slot::Presets)
+        .find(|definition| definition_is_named(definition, name))
+        .and_then(|definition| JSONValue::parse(definition).ok())
+        .and_then(|json| find_key(&json, "preset"))
+}
+
+pub fn define_preset(
+    storage: &mut dyn Storage,
+    buffer: &mut SlotBuffer,
+    json: &JSONValue,
+    json_str: &str,
+) -> Result<(), CommandError> {
+    let name = json.get_key_value("name")?;
+    json.get_key_value("preset")?.get_key_value("type")?.read_string()?;
+    let definition = Some(json_str.trim_end());
+    store_definitions(storage, buffer, Slot::Presets, name.read_string()?, definition)
+}
+
+pub fn delete_preset(
+    storage: &mut dyn Storage,
+    buffer: &mut SlotBuffer,
+    name: &str,
+) -> Result<(), CommandError> {
+    store_definitions(storage, buffer, Slot::Presets, name, None)
+}
+
+// Rewrites the stored definitions without the named one, followed by the new definition if any.
+// They're rebuilt in the caller's buffer, the slot can't be erased while it's still being read.
+pub(crate) fn store_definitions(
+    storage: &mut dyn Storage,
+    buffer: &mut SlotBuffer,
+    slot: Slot,
+    name: &str,
+    new_definition: Option<&str>,
+) -> Result<(), CommandError> {
+    buffer.clear();
+    for definition in stored_definitions(storage, slot)
+        .filter(|definition| !definition_is_named(definition, name))
+        .chain(new_definition)
+    {
+        buffer
+            .extend_from_slice(definition.as_bytes())
+            .and_then(|_| buffer.push(b'\n').map_err(|_| ()))
+            .map_err(|_| StorageError::TooLarge)?;
+    }
+
+    storage.store(slot, buffer)?;
+    Ok(())
+}
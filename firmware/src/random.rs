@@ -0,0 +1,88 @@
+use core::f32::consts::{FRAC_1_SQRT_2, SQRT_2};
+#[cfg(not(test))]
+use micromath::F32Ext;
+
+// Deterministic randomness for the effects. Nothing is kept between frames: every random choice is
+// a hash of the event's seed and where and when it's made, so the host simulator can render the
+// exact same frame by running the same integer maths.
+
+// Chris Wellons' lowbias32 integer hash
+fn mix(mut x: u32) -> u32 {
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb_352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846c_a68b);
+    x ^= x >> 16;
+    x
+}
+
+// A random number for the given seed and coordinates, e.g. a pixel and a time slot.
+// The golden ratio offset keeps hash(0, 0, 0) from being 0.
+pub fn hash(seed: u32, x: u32, y: u32) -> u32 {
+    mix(seed.wrapping_add(mix(x.wrapping_add(mix(y.wrapping_add(0x9e37_79b9))))))
+}
+
+// Gradient (Perlin) noise, smooth in both directions and roughly between -1 and 1. The gradients
+// at the grid points are picked by hash() from 8 directions.
+pub fn noise(seed: u32, x: f32, y: f32) -> f32 {
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let (ix, iy) = (x0 as i32, y0 as i32);
+
+    let corner = |dx: i32, dy: i32| -> f32 {
+        let (gx, gy) = GRADIENTS[(hash(seed, (ix + dx) as u32, (iy + dy) as u32) & 7) as usize];
+        gx * (fx - dx as f32) + gy * (fy - dy as f32)
+    };
+    let (u, v) = (fade(fx), fade(fy));
+    let bottom = corner(0, 0) + (corner(1, 0) - corner(0, 0)) * u;
+    let top = corner(0, 1) + (corner(1, 1) - corner(0, 1)) * u;
+    // the gradients reach +-0.71 at most, stretch that to +-1
+    (bottom + (top - bottom) * v) * SQRT_2
+}
+
+const GRADIENTS: [(f32, f32); 8] = [
+    (1.0, 0.0),
+    (FRAC_1_SQRT_2, FRAC_1_SQRT_2),
+    (0.0, 1.0),
+    (-FRAC_1_SQRT_2, FRAC_1_SQRT_2),
+    (-1.0, 0.0),
+    (-FRAC_1_SQRT_2, -FRAC_1_SQRT_2),
+    (0.0, -1.0),
+    (FRAC_1_SQRT_2, -FRAC_1_SQRT_2),
+];
+
+// 6t^5 - 15t^4 + 10t^3, so the noise is smooth across the grid lines
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+// The top 24 bits as a fraction between 0 (inclusive) and 1 (exclusive)
+pub fn unit(value: u32) -> f32 {
+    (value >> 8) as f32 / (1 << 24) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The host simulator relies on these, changing them changes every rendered effect
+    #[test]
+    fn hash_values_are_stable() {
+        assert_eq!(hash(0, 0, 0), 2488251732);
+        assert_eq!(hash(42, 7, 3), 2333254468);
+        assert!(unit(u32::MAX) < 1.0);
+    }
+
+    #[test]
+    fn noise_is_smooth_and_bounded() {
+        let mut previous = noise(7, 0.0, 3.3);
+        for i in 1..1000 {
+            let value = noise(7, i as f32 * 0.01, 3.3);
+            assert!(value.abs() <= 1.0);
+            assert!((value - previous).abs() < 0.05);
+            previous = value;
+        }
+        // zero at the grid points
+        assert_eq!(noise(7, 2.0, 5.0), 0.0);
+    }
+}
@@ -0,0 +1,29 @@
+use heapless::Vec;
+
+// Persistent storage for configuration uploaded by the host, implemented by the hardware crate
+// on top of the microcontroller's flash. Every slot holds one blob which is replaced as a whole.
+#[derive(Copy, Clone, PartialEq)]
+pub enum Slot {
+    IdleScene,
+    Presets,
+    Palettes,
+}
+
+// largest blob a slot can hold
+pub const SLOT_SIZE: usize = 8192 - 8;
+
+// Room to rebuild a slot's contents in before they're stored. Too big for the stack, the board
+// keeps it in a static.
+pub type SlotBuffer = Vec<u8, SLOT_SIZE>;
+
+#[derive(Debug)]
+pub enum StorageError {
+    TooLarge,
+    Flash,
+}
+
+pub trait Storage {
+    // None when nothing was ever stored in the slot
+    fn load(&self, slot: Slot) -> Option<&[u8]>;
+    fn store(&mut self, slot: Slot, data: &[u8]) -> Result<(), StorageError>;
+}
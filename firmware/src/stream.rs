@@ -0,0 +1,238 @@
+use core::fmt::Write;
+
+use crate::{
+    color::{mix, Interpolation},
+    json_events::Response,
+    new_strips::{Strips, STRIP_INDICES, STRIP_LENGTH},
+    structs::PixelRange,
+};
+use smart_leds_trait::RGB8;
+
+// Raw frames computed on the host, e.g. video mapped onto the strips. They come in over the same
+// serial connection as the commands, as binary packets:
+//   0xFE, strip_idx, frame number (u16), start pixel (u16), pixel count (u16), pixel count * [r, g, b],
+//   checksum
+// with the numbers little endian and the checksum the wrapping sum of every byte after the 0xFE.
+// 0xFE never appears in UTF-8 text, so a packet can't be mistaken for a command line.
+// The frames go into a layer drawn over the events. When they stop arriving the layer fades out
+// and the events show again.
+pub const STREAM_SYNC: u8 = 0xFE;
+const HEADER_LEN: usize = 8;
+pub const DEFAULT_STREAM_TIMEOUT: f32 = 0.5;
+pub const DEFAULT_STREAM_FADE: f32 = 1.0;
+
+struct StreamStrip {
+    pixels: [RGB8; STRIP_LENGTH],
+    // the pixels any frame since the stream started has set, the events show through elsewhere
+    covered: PixelRange,
+    last_received: f32,
+    last_frame: Option<u16>,
+}
+
+impl StreamStrip {
+    const fn new() -> Self {
+        StreamStrip {
+            pixels: [RGB8 { r: 0, g: 0, b: 0 }; STRIP_LENGTH],
+            covered: PixelRange { start: 0, end: 0 },
+            last_received: 0.0,
+            last_frame: None,
+        }
+    }
+}
+
+pub struct StreamLayer {
+    strips: (StreamStrip, StreamStrip),
+    // seconds without frames before the layer starts fading out
+    pub timeout: f32,
+    // seconds the fade out takes
+    pub fade: f32,
+    pub frames: u32,
+    // arrived after a newer frame, and were dropped
+    pub late_frames: u32,
+    // skipped frame numbers
+    pub missed_frames: u32,
+    // failed the checksum or didn't fit the strip
+    pub bad_packets: u32,
+}
+
+impl Default for StreamLayer {
+    fn default() -> Self {
+        StreamLayer::new()
+    }
+}
+
+impl StreamLayer {
+    pub const fn new() -> Self {
+        StreamLayer {
+            strips: (StreamStrip::new(), StreamStrip::new()),
+            timeout: DEFAULT_STREAM_TIMEOUT,
+            fade: DEFAULT_STREAM_FADE,
+            frames: 0,
+            late_frames: 0,
+            missed_frames: 0,
+            bad_packets: 0,
+        }
+    }
+
+    // Takes the packet at the start of buf, which must start with STREAM_SYNC. Returns how many
+    // bytes were used up, or None when the packet hasn't completely arrived yet. A broken packet
+    // only uses up its first byte, so the next packet or command line is found again.
+    pub fn take_packet(&mut self, buf: &[u8], real_seconds: f32) -> Option<usize> {
+        if buf.len() < HEADER_LEN {
+            return None;
+        }
+        let read_u16 = |idx: usize| u16::from_le_bytes([buf[idx], buf[idx + 1]]);
+        let strip_idx = buf[1] as usize;
+        let frame = read_u16(2);
+        let start = read_u16(4);
+        let count = read_u16(6);
+
+        if start as usize + count as usize > STRIP_LENGTH {
+            self.bad_packets += 1;
+            return Some(1);
+        }
+        let len = HEADER_LEN + count as usize * 3 + 1;
+        if buf.len() < len {
+            return None;
+        }
+        let checksum = buf[1..len - 1].iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        if checksum != buf[len - 1] {
+            self.bad_packets += 1;
+            return Some(1);
+        }
+
+        let strip = match strip_idx {
+            idx if idx == STRIP_INDICES.0 => &mut self.strips.0,
+            idx if idx == STRIP_INDICES.1 => &mut self.strips.1,
+            _ => return Some(len),
+        };
+        if let Some(last_frame) = strip.last_frame {
+            let ahead = frame.wrapping_sub(last_frame) as i16;
+            if ahead <= 0 {
+                self.late_frames += 1;
+                return Some(len);
+            }
+            self.missed_frames += ahead as u32 - 1;
+        }
+
+        let range = PixelRange {
+            start,
+            end: start + count,
+        };
+        for (pixel, rgb) in strip.pixels[range.indices()]
+            .iter_mut()
+            .zip(buf[HEADER_LEN..len - 1].chunks_exact(3))
+        {
+            *pixel = RGB8 {
+                r: rgb[0],
+                g: rgb[1],
+                b: rgb[2],
+            };
+        }
+        strip.covered = if strip.covered.start == strip.covered.end {
+            range
+        } else {
+            PixelRange {
+                start: strip.covered.start.min(range.start),
+                end: strip.covered.end.max(range.end),
+            }
+        };
+        strip.last_received = real_seconds;
+        strip.last_frame = Some(frame);
+        self.frames += 1;
+        Some(len)
+    }
+
+    // Draws the streamed pixels over the events, fading them out once frames stop arriving
+    pub fn composite(&mut self, real_seconds: f32, strips: &mut Strips) {
+        let (timeout, fade) = (self.timeout, self.fade);
+        for (stream, strip) in [
+            (&mut self.strips.0, &mut strips.strips.0),
+            (&mut self.strips.1, &mut strips.strips.1),
+        ] {
+            if stream.covered.start == stream.covered.end {
+                continue;
+            }
+
+            let silent = real_seconds - stream.last_received - timeout;
+            let opacity = if silent <= 0.0 {
+                1.0
+            } else if silent < fade {
+                1.0 - silent / fade
+            } else {
+                // faded out completely, the next frame starts a new stream
+                *stream = StreamStrip::new();
+                continue;
+            };
+
+            for idx in stream.covered.indices() {
+                strip[idx] = mix(strip[idx], stream.pixels[idx], opacity, Interpolation::Rgb);
+            }
+        }
+    }
+
+    pub fn write_json(&self, response: &mut Response) {
+        let _ = writeln!(
+            response,
+            "{{\"type\":\"stream\",\"frames\":{},\"late_frames\":{},\"missed_frames\":{},\"bad_packets\":{}}}",
+            self.frames, self.late_frames, self.missed_frames, self.bad_packets,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(frame: u16, start: u16, color: RGB8, count: u16) -> heapless::Vec<u8, 64> {
+        let mut packet = heapless::Vec::new();
+        let _ = packet.extend_from_slice(&[STREAM_SYNC, STRIP_INDICES.0 as u8]);
+        for number in [frame, start, count] {
+            let _ = packet.extend_from_slice(&number.to_le_bytes());
+        }
+        for _ in 0..count {
+            let _ = packet.extend_from_slice(&[color.r, color.g, color.b]);
+        }
+        let checksum = packet[1..].iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        let _ = packet.push(checksum);
+        packet
+    }
+
+    #[test]
+    fn frames_cover_the_events_then_fade_out() {
+        let mut layer = StreamLayer::new();
+        let green = RGB8 { r: 0, g: 200, b: 0 };
+        let blue = RGB8 { r: 0, g: 0, b: 200 };
+
+        let first = packet(7, 10, green, 5);
+        assert_eq!(layer.take_packet(&first[..first.len() - 1], 0.0), None);
+        assert_eq!(layer.take_packet(&first, 0.0), Some(first.len()));
+        // an older frame is dropped, a corrupted one only loses its sync byte
+        assert_eq!(layer.take_packet(&packet(6, 10, blue, 5), 0.0), Some(first.len()));
+        let mut corrupted = packet(9, 10, blue, 5);
+        corrupted[9] ^= 1;
+        assert_eq!(layer.take_packet(&corrupted, 0.0), Some(1));
+        assert_eq!((layer.frames, layer.late_frames, layer.bad_packets), (1, 1, 1));
+
+        let events = Strips {
+            strips: ([RGB8 { r: 100, g: 0, b: 0 }; STRIP_LENGTH], [RGB8 { r: 0, g: 0, b: 0 }; STRIP_LENGTH]),
+        };
+        let mut strips = events;
+        layer.composite(0.4, &mut strips);
+        assert_eq!(strips.strips.0[10], green);
+        assert_eq!(strips.strips.0[9], RGB8 { r: 100, g: 0, b: 0 });
+
+        // half way through the fade
+        let mut strips = events;
+        layer.composite(DEFAULT_STREAM_TIMEOUT + DEFAULT_STREAM_FADE / 2.0, &mut strips);
+        assert_eq!(strips.strips.0[12], RGB8 { r: 50, g: 100, b: 0 });
+
+        let mut strips = events;
+        layer.composite(DEFAULT_STREAM_TIMEOUT + DEFAULT_STREAM_FADE, &mut strips);
+        assert_eq!(strips.strips.0[12], RGB8 { r: 100, g: 0, b: 0 });
+        // a new stream may number its frames from the start again
+        let restarted = packet(0, 0, blue, 1);
+        layer.take_packet(&restarted, 3.0);
+        assert_eq!(layer.late_frames, 1);
+    }
+}
@@ -1,6 +1,32 @@
+use crate::clock::{TimeBase, Timestamp};
+use crate::color::Interpolation;
+use crate::new_strips::STRIP_LENGTH;
+use crate::palettes::PaletteRef;
 use micromath::F32Ext;
 use smart_leds_trait::RGB8;
 
+// Pixels start..end of a strip, painted by a single event
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct PixelRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PixelRange {
+    pub fn single(pixel_idx: u16) -> Self {
+        PixelRange {
+            start: pixel_idx,
+            end: pixel_idx + 1,
+        }
+    }
+
+    // the part of the range which is on the strip
+    pub fn indices(&self) -> core::ops::Range<usize> {
+        (self.start as usize).min(STRIP_LENGTH)..(self.end as usize).min(STRIP_LENGTH)
+    }
+}
+
+#[derive(Clone)]
 pub struct ConstantEvent {
     pub color: RGB8,
     pub duration: f32,
@@ -8,9 +34,10 @@ pub struct ConstantEvent {
     pub fadeout_duration: u32,
     pub fade_power: u8,
     pub strip_idx: usize,
-    pub pixel_idx: usize,
+    pub pixels: PixelRange,
 }
 
+#[derive(Clone)]
 pub struct MessageEvent {
     pub color: RGB8,
     pub message_width: u16,
@@ -18,8 +45,22 @@ pub struct MessageEvent {
     pub strip_idx: usize,
     pub start_idx: usize,
     pub end_idx: usize,
+    // pixels behind the head over which a comet tail fades out, 0 for a plain blob
+    pub tail_length: u16,
+    // the tail fades from the message colour into this one
+    pub tail_color: RGB8,
+    // set when no tail colour was given, so a palette recolours the tail along with the message
+    pub tail_follows_color: bool,
 }
 
+impl MessageEvent {
+    // how far behind the head the message still lights pixels
+    pub fn trailing_extent(&self) -> f32 {
+        (self.message_width as f32 / 2.0).max(self.tail_length as f32)
+    }
+}
+
+#[derive(Clone)]
 pub struct AttackDecayEvent {
     pub color: RGB8,
     pub attack_duration: f32,
@@ -30,12 +71,13 @@ pub struct AttackDecayEvent {
     pub end_idx: usize,
 }
 
+#[derive(Clone)]
 pub struct HeartbeatEvent {
     pub color: RGB8,
     // total duration for which to play the effect
     pub duration: f32,
     pub strip_idx: usize,
-    pub pixel_idx: usize,
+    pub pixels: PixelRange,
     pub first_pulse_attack: f32,
     pub first_pulse_decay: f32,
     pub second_pulse_attack: f32,
@@ -45,33 +87,215 @@ pub struct HeartbeatEvent {
     pub dimness: f32,
 }
 
+// gradients with more stops than this are rejected
+pub const MAX_GRADIENT_STOPS: usize = 4;
+
+#[derive(Copy, Clone)]
+pub struct GradientStop {
+    pub color: RGB8,
+    // where along the range the colour is reached, 0 at the start and 255 at the end
+    pub position: u8,
+}
+
+#[derive(Clone)]
+pub struct GradientEvent {
+    pub stops: [GradientStop; MAX_GRADIENT_STOPS],
+    pub stop_count: u8,
+    pub interpolation: Interpolation,
+    pub strip_idx: usize,
+    pub pixels: PixelRange,
+    // pixels per second the gradient moves along the range, wrapping around at the end
+    pub scroll_speed: f32,
+    pub duration: f32,
+    pub fadein_duration: f32,
+    pub fadeout_duration: f32,
+}
+
+// Rings spreading out both ways from a pixel, like a stone dropped in water
+#[derive(Clone)]
+pub struct RippleEvent {
+    pub color: RGB8,
+    pub center_idx: u16,
+    // pixels per second
+    pub speed: f32,
+    // of each ring, in pixels
+    pub width: u16,
+    // how far the rings travel, at most to the ends of the strip
+    pub radius: u16,
+    // brightness lost per pixel travelled, as in exp(-decay * distance)
+    pub decay: f32,
+    pub rings: u8,
+    // seconds between one ring and the next leaving the centre
+    pub ring_interval: f32,
+    pub strip_idx: usize,
+}
+
+// the most colours a sparkle or noise event picks from
+pub const MAX_COLORS: usize = 4;
+
+#[derive(Clone)]
+pub struct SparkleEvent {
+    // every sparkle picks one of these
+    pub colors: [RGB8; MAX_COLORS],
+    pub color_count: u8,
+    // chance of a pixel sparkling in each of its attack + decay cycles, between 0 and 1
+    pub density: f32,
+    pub attack: f32,
+    pub decay: f32,
+    pub duration: f32,
+    // the same seed gives the same sparkles, on the board and in the simulator
+    pub seed: u32,
+    pub strip_idx: usize,
+    pub pixels: PixelRange,
+}
+
+// Slowly flowing texture: noise along the strip, moving through time, coloured by the colour list
+#[derive(Clone)]
+pub struct NoiseEvent {
+    pub colors: [RGB8; MAX_COLORS],
+    pub color_count: u8,
+    // noise cells per pixel, smaller is smoother
+    pub scale: f32,
+    // noise cells per second
+    pub speed: f32,
+    pub brightness: f32,
+    pub duration: f32,
+    pub seed: u32,
+    pub strip_idx: usize,
+    pub pixels: PixelRange,
+}
+
+pub const MAX_GROW_BRANCHES: usize = 16;
+
+// One stretch of a growing network, from start_idx towards end_idx (which may be lower). The front
+// spreads from the origin at speed pixels per second, so a branch only starts once the front has
+// travelled delay pixels to reach it. After growing and holding, the network withers back from the
+// tips to the origin.
+#[derive(Clone)]
+pub struct GrowEvent {
+    pub color: RGB8,
+    pub strip_idx: usize,
+    pub start_idx: u16,
+    pub end_idx: u16,
+    pub delay: f32,
+    // distance from the origin to the furthest tip of the whole network, the same for every branch
+    pub reach: f32,
+    pub speed: f32,
+    // pixels over which the front fades in, as in the attack decay event
+    pub smoothing: f32,
+    // seconds fully grown
+    pub hold: f32,
+    // seconds taken to wither back to the origin
+    pub wither: f32,
+}
+
+#[derive(Clone)]
 pub enum Event {
     Message(MessageEvent),
     Constant(ConstantEvent),
     Heartbeat(HeartbeatEvent),
+    Gradient(GradientEvent),
+    Sparkle(SparkleEvent),
+    Ripple(RippleEvent),
+    Noise(NoiseEvent),
+    Grow(GrowEvent),
+}
+
+impl Event {
+    pub fn strip_idx(&self) -> usize {
+        match self {
+            Event::Message(e) => e.strip_idx,
+            Event::Constant(e) => e.strip_idx,
+            Event::Heartbeat(e) => e.strip_idx,
+            Event::Gradient(e) => e.strip_idx,
+            Event::Sparkle(e) => e.strip_idx,
+            Event::Ripple(e) => e.strip_idx,
+            Event::Noise(e) => e.strip_idx,
+            Event::Grow(e) => e.strip_idx,
+        }
+    }
+
+    // Changes every colour the event is drawn with
+    pub fn map_colors(&mut self, f: impl Fn(RGB8) -> RGB8) {
+        match self {
+            Event::Message(e) => {
+                e.color = f(e.color);
+                e.tail_color = f(e.tail_color);
+            }
+            Event::Constant(e) => e.color = f(e.color),
+            Event::Heartbeat(e) => e.color = f(e.color),
+            Event::Ripple(e) => e.color = f(e.color),
+            Event::Grow(e) => e.color = f(e.color),
+            Event::Gradient(e) => {
+                for stop in e.stops.iter_mut() {
+                    stop.color = f(stop.color);
+                }
+            }
+            Event::Sparkle(e) => e.colors.iter_mut().for_each(|color| *color = f(*color)),
+            Event::Noise(e) => e.colors.iter_mut().for_each(|color| *color = f(*color)),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum LoopMode {
+    // play once, then get removed
+    Once,
+    // play the given number of times back to back
+    Times(u16),
+    // restart from the beginning until cancelled or cleared
+    Forever,
+    // play forwards then backwards until cancelled or cleared
+    PingPong,
 }
 
 pub struct EventWrapper {
     pub event: Event,
+    // measured in the event's time base
     pub start_time: Option<f32>,
+    pub loop_mode: LoopMode,
+    // set by the host so the event can be cancelled later
+    pub id: Option<u16>,
+    // beat based events follow the global tempo, their durations and paces are per beat
+    pub time_base: TimeBase,
+    // speeds up (> 1.0) or slows down (< 1.0) just this event
+    pub time_scale: f32,
+    // part of the idle scene, removed as soon as the host sends events again
+    pub idle: bool,
+    // which events get evicted first when the queue is full, lowest first
+    pub priority: u8,
+    // where the event's colours come from, they're looked up again every frame
+    pub palette: Option<PaletteRef>,
+    // turns around the colour wheel per second (or beat) the event's colours cycle through
+    pub hue_rotation: f32,
 }
 
 pub trait Duration {
     fn duration(&self) -> f32;
     fn active(&self) -> bool;
-    fn finished(&self, timer_seconds: f32) -> bool;
-    fn activate(&mut self, timer_seconds: f32);
+    fn elapsed(&self, now: Timestamp) -> f32;
+    fn finished(&self, now: Timestamp) -> bool;
+    fn activate(&mut self, now: Timestamp);
+    fn local_time(&self, now: Timestamp) -> f32;
 }
 
 impl Duration for EventWrapper {
     fn duration(&self) -> f32 {
         match &self.event {
             Event::Message(e) => {
-                ((e.end_idx as f32 - e.start_idx as f32).abs() + 1.0 + e.message_width as f32 / 2.0)
-                    / e.pace
+                ((e.end_idx as f32 - e.start_idx as f32).abs() + 1.0 + e.trailing_extent()) / e.pace
             },
             Event::Constant(e) => e.duration as f32,
             Event::Heartbeat(e) => e.duration,
+            Event::Gradient(e) => e.duration,
+            Event::Sparkle(e) => e.duration,
+            Event::Noise(e) => e.duration,
+            Event::Grow(e) => e.reach / e.speed + e.hold + e.wither,
+            Event::Ripple(e) => {
+                // until the last ring has passed the radius
+                (e.radius as f32 + e.width as f32 / 2.0) / e.speed
+                    + e.rings.saturating_sub(1) as f32 * e.ring_interval
+            }
         }
     }
 
@@ -79,15 +303,98 @@ impl Duration for EventWrapper {
         self.start_time.is_some()
     }
 
-    fn finished(&self, timer_seconds: f32) -> bool {
+    // time since activation, scaled by the event's time scale
+    fn elapsed(&self, now: Timestamp) -> f32 {
         if let Some(start_time) = self.start_time {
-            timer_seconds > start_time + self.duration()
+            (now.in_base(self.time_base) - start_time) * self.time_scale
         } else {
-            false
+            0.0
+        }
+    }
+
+    fn finished(&self, now: Timestamp) -> bool {
+        if !self.active() {
+            return false;
+        }
+
+        match self.loop_mode {
+            LoopMode::Once => self.elapsed(now) > self.duration(),
+            LoopMode::Times(count) => self.elapsed(now) > self.duration() * count as f32,
+            LoopMode::Forever | LoopMode::PingPong => false,
         }
     }
 
-    fn activate(&mut self, timer_seconds: f32) {
-        self.start_time = Some(timer_seconds);
+    fn activate(&mut self, now: Timestamp) {
+        self.start_time = Some(now.in_base(self.time_base));
+    }
+
+    // time since the start of the current loop iteration, which is what the painters draw
+    fn local_time(&self, now: Timestamp) -> f32 {
+        let elapsed = self.elapsed(now);
+        let duration = self.duration();
+        if duration <= 0.0 {
+            return elapsed;
+        }
+
+        match self.loop_mode {
+            LoopMode::Once => elapsed,
+            LoopMode::Times(_) | LoopMode::Forever => elapsed % duration,
+            LoopMode::PingPong => {
+                let time_in_cycle = elapsed % (duration * 2.0);
+                if time_in_cycle > duration {
+                    duration * 2.0 - time_in_cycle
+                } else {
+                    time_in_cycle
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn constant_wrapper(loop_mode: LoopMode) -> EventWrapper {
+        EventWrapper {
+            event: Event::Constant(ConstantEvent {
+                color: RGB8 { r: 100, g: 0, b: 0 },
+                duration: 2.0,
+                fadein_duration: 0,
+                fadeout_duration: 0,
+                fade_power: 0,
+                strip_idx: 0,
+                pixels: PixelRange::single(0),
+            }),
+            start_time: Some(10.0),
+            loop_mode,
+            id: None,
+            time_base: TimeBase::Seconds,
+            time_scale: 1.0,
+            idle: false,
+            priority: 0,
+            palette: None,
+            hue_rotation: 0.0,
+        }
+    }
+
+    fn at(seconds: f32) -> Timestamp {
+        Timestamp { seconds, beats: seconds }
+    }
+
+    #[test]
+    fn loop_modes_finish() {
+        assert!(constant_wrapper(LoopMode::Once).finished(at(12.5)));
+        assert!(!constant_wrapper(LoopMode::Times(2)).finished(at(12.5)));
+        assert!(constant_wrapper(LoopMode::Times(2)).finished(at(14.5)));
+        assert!(!constant_wrapper(LoopMode::Forever).finished(at(1000.0)));
+        assert!(!constant_wrapper(LoopMode::PingPong).finished(at(1000.0)));
+    }
+
+    #[test]
+    fn loop_modes_local_time() {
+        assert_eq!(constant_wrapper(LoopMode::Forever).local_time(at(12.5)), 0.5);
+        assert_eq!(constant_wrapper(LoopMode::PingPong).local_time(at(12.5)), 1.5);
+        assert_eq!(constant_wrapper(LoopMode::PingPong).local_time(at(14.5)), 0.5);
     }
 }
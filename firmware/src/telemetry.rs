@@ -0,0 +1,131 @@
+use core::fmt::Write;
+
+use crate::{
+    crash::CrashReport,
+    governor::FrameGovernor,
+    json_events::{Response, EVENT_TYPES, MAX_LINE_LEN, PROTOCOL_VERSION},
+    new_strips::{MAX_EVENTS, SERIAL_NUM, STRIP_INDICES, STRIP_LENGTH},
+};
+
+pub const FIRMWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+// set by build.rs
+pub const GIT_HASH: &str = env!("GIT_HASH");
+pub const BUILD_TIME: &str = env!("BUILD_TIME");
+// how quickly the reported frame rate follows changes, between 0 and 1
+const FRAME_RATE_SMOOTHING: f32 = 0.05;
+
+// Counters the main loop keeps about itself, reported to the host by the "status" command
+pub struct Telemetry {
+    pub uptime_seconds: f32,
+    pub frames: u32,
+    pub frame_rate: f32,
+    // reset every time the status is reported, so each poll sees the worst frame since the last
+    pub worst_frame_seconds: f32,
+    last_frame_seconds: Option<f32>,
+    pub rx_high_water: usize,
+    pub commands: u32,
+    pub parse_errors: u32,
+    // events lost because the queue was full
+    pub evicted: u32,
+    pub dropped: u32,
+    pub last_crash: CrashReport,
+}
+
+impl Default for Telemetry {
+    fn default() -> Self {
+        Telemetry::new()
+    }
+}
+
+impl Telemetry {
+    pub const fn new() -> Self {
+        Telemetry {
+            uptime_seconds: 0.0,
+            frames: 0,
+            frame_rate: 0.0,
+            worst_frame_seconds: 0.0,
+            last_frame_seconds: None,
+            rx_high_water: 0,
+            commands: 0,
+            parse_errors: 0,
+            evicted: 0,
+            dropped: 0,
+            last_crash: CrashReport::none(),
+        }
+    }
+
+    // Call once per frame with the real (not virtual) time
+    pub fn record_frame(&mut self, real_seconds: f32) {
+        self.uptime_seconds = real_seconds;
+        self.frames += 1;
+
+        if let Some(last_frame_seconds) = self.last_frame_seconds {
+            let frame_seconds = real_seconds - last_frame_seconds;
+            if frame_seconds > self.worst_frame_seconds {
+                self.worst_frame_seconds = frame_seconds;
+            }
+            if frame_seconds > 0.0 {
+                self.frame_rate += (1.0 / frame_seconds - self.frame_rate) * FRAME_RATE_SMOOTHING;
+            }
+        }
+        self.last_frame_seconds = Some(real_seconds);
+    }
+
+    pub fn record_rx_len(&mut self, rx_len: usize) {
+        if rx_len > self.rx_high_water {
+            self.rx_high_water = rx_len;
+        }
+    }
+
+    pub fn write_status(
+        &mut self,
+        response: &mut Response,
+        active_events: usize,
+        governor: &FrameGovernor,
+    ) {
+        let _ = write!(
+            response,
+            "{{\"type\":\"status\",\"uptime\":{:.1},\"frames\":{},\"fps\":{:.1},\"worst_frame_ms\":{:.1},\
+             \"active_events\":{},\"max_events\":{},\"rx_high_water\":{},\"commands\":{},\
+             \"parse_errors\":{},\"evicted\":{},\"dropped\":{},\"strip_indices\":[{},{}],\"version\":\"{}\",\"last_crash\":",
+            self.uptime_seconds,
+            self.frames,
+            self.frame_rate,
+            self.worst_frame_seconds * 1000.0,
+            active_events,
+            MAX_EVENTS,
+            self.rx_high_water,
+            self.commands,
+            self.parse_errors,
+            self.evicted,
+            self.dropped,
+            STRIP_INDICES.0,
+            STRIP_INDICES.1,
+            FIRMWARE_VERSION,
+        );
+        self.last_crash.write_json(response);
+        let _ = write!(response, ",\"frame\":");
+        governor.write_json(response);
+        let _ = writeln!(response, "}}");
+        self.worst_frame_seconds = 0.0;
+    }
+}
+
+// Lets the host check which build it is talking to and what that build supports
+pub fn write_hello(response: &mut Response) {
+    let _ = write!(
+        response,
+        "{{\"type\":\"hello\",\"protocol\":{},\"version\":\"{}\",\"git_hash\":\"{}\",\
+         \"build_time\":{},\"serial\":\"{}\",\"event_types\":[",
+        PROTOCOL_VERSION, FIRMWARE_VERSION, GIT_HASH, BUILD_TIME, SERIAL_NUM,
+    );
+    for (i, event_type) in EVENT_TYPES.iter().enumerate() {
+        let separator = if i == 0 { "" } else { "," };
+        let _ = write!(response, "{}\"{}\"", separator, event_type);
+    }
+    let _ = writeln!(
+        response,
+        "],\"strip_indices\":[{},{}],\"strip_length\":{},\"max_events\":{},\"max_line_len\":{}}}",
+        STRIP_INDICES.0, STRIP_INDICES.1, STRIP_LENGTH, MAX_EVENTS, MAX_LINE_LEN,
+    );
+}
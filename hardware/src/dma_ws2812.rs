@@ -0,0 +1,165 @@
+use bsp::hal::pac::{sercom0, DMAC, MCLK};
+use firmware::new_strips::STRIP_LENGTH;
+use itsybitsy_m4 as bsp;
+use smart_leds_trait::{SmartLedsWrite, RGB8};
+
+// WS2812 output which doesn't keep the CPU busy: every bit of the LED protocol is encoded as three
+// SPI bits (100 for a 0, 110 for a 1) at 2.4MHz, and a DMA channel feeds the encoded frame to the
+// SERCOM. write() only encodes and starts the transfer, so both strips refresh at the same time
+// while the next frame is computed and USB keeps being served.
+// Only pads which can be SPI data out work: d2 is SERCOM0 PAD3, the second strip moves from d3 to
+// the MOSI pin, which is SERCOM1 PAD0.
+
+const BYTES_PER_LED: usize = 9;
+// low for 333us after the frame, enough for the LEDs to latch the colours
+const RESET_BYTES: usize = 100;
+const BUFFER_LEN: usize = STRIP_LENGTH * BYTES_PER_LED + RESET_BYTES;
+// the SERCOMs run from the 48MHz DFLL: 48MHz / (2 * (9 + 1)) = 2.4MHz
+const SPI_BAUD: u8 = 9;
+
+// DMA trigger sources, from the DMAC chapter of the datasheet
+const SERCOM0_TX_TRIGGER: u32 = 0x05;
+const SERCOM1_TX_TRIGGER: u32 = 0x07;
+
+// register bits the PAC doesn't give us nicer names for
+const CHCTRLA_ENABLE: u32 = 1 << 1;
+const CHCTRLA_TRIGACT_BURST: u32 = 2 << 20;
+const BTCTRL_VALID: u16 = 1 << 0;
+const BTCTRL_SRCINC: u16 = 1 << 10;
+
+// The DMAC reads its transfer descriptors from RAM, one per channel, 128 bit aligned
+#[repr(C, align(16))]
+#[derive(Copy, Clone)]
+struct Descriptor {
+    btctrl: u16,
+    btcnt: u16,
+    srcaddr: u32,
+    dstaddr: u32,
+    descaddr: u32,
+}
+
+const EMPTY_DESCRIPTOR: Descriptor = Descriptor {
+    btctrl: 0,
+    btcnt: 0,
+    srcaddr: 0,
+    dstaddr: 0,
+    descaddr: 0,
+};
+
+static mut DESCRIPTORS: [Descriptor; 2] = [EMPTY_DESCRIPTOR; 2];
+static mut WRITEBACK: [Descriptor; 2] = [EMPTY_DESCRIPTOR; 2];
+static mut BUFFERS: [[u8; BUFFER_LEN]; 2] = [[0; BUFFER_LEN]; 2];
+
+pub struct DmaWs2812 {
+    channel: usize,
+    data_address: u32,
+}
+
+// Sets up the DMAC and both SERCOMs, the pins must already be muxed to their SERCOM and the
+// SERCOM core clocks running at 48MHz
+pub fn strips(
+    dmac: DMAC,
+    sercoms: (&sercom0::RegisterBlock, &sercom0::RegisterBlock),
+    mclk: &mut MCLK,
+) -> (DmaWs2812, DmaWs2812) {
+    mclk.ahbmask.modify(|_, w| w.dmac_().set_bit());
+    mclk.apbamask.modify(|_, w| w.sercom0_().set_bit().sercom1_().set_bit());
+
+    unsafe {
+        dmac.baseaddr.write(|w| w.bits(DESCRIPTORS.as_ptr() as u32));
+        dmac.wrbaddr.write(|w| w.bits(WRITEBACK.as_ptr() as u32));
+    }
+    dmac.ctrl.write(|w| {
+        w.dmaenable().set_bit();
+        w.lvlen0().set_bit()
+    });
+
+    (
+        DmaWs2812::new(0, sercoms.0, 3, SERCOM0_TX_TRIGGER),
+        DmaWs2812::new(1, sercoms.1, 0, SERCOM1_TX_TRIGGER),
+    )
+}
+
+impl DmaWs2812 {
+    fn new(channel: usize, sercom: &sercom0::RegisterBlock, data_out_pad: u8, trigger: u32) -> Self {
+        let spi = sercom.spim();
+        // transmit only, so the DMA never has to drain received bytes
+        let dopo = if data_out_pad == 3 { 2 } else { 0 };
+        spi.ctrla.write(|w| unsafe { w.mode().spi_master().dopo().bits(dopo) });
+        spi.baud.write(|w| unsafe { w.baud().bits(SPI_BAUD) });
+        spi.ctrla.modify(|_, w| w.enable().set_bit());
+        while spi.syncbusy.read().enable().bit_is_set() {}
+
+        let dmac = unsafe { &*DMAC::ptr() };
+        dmac.channel[channel]
+            .chctrla
+            .write(|w| unsafe { w.bits((trigger << 8) | CHCTRLA_TRIGACT_BURST) });
+
+        DmaWs2812 {
+            channel,
+            data_address: &spi.data as *const _ as u32,
+        }
+    }
+
+    // The DMAC disables the channel once the last byte is out
+    pub fn busy(&self) -> bool {
+        let dmac = unsafe { &*DMAC::ptr() };
+        dmac.channel[self.channel].chctrla.read().bits() & CHCTRLA_ENABLE != 0
+    }
+
+    fn start(&mut self) {
+        unsafe {
+            let buffer = &BUFFERS[self.channel];
+            // with an incrementing source the DMAC wants the address just past the data
+            DESCRIPTORS[self.channel] = Descriptor {
+                btctrl: BTCTRL_VALID | BTCTRL_SRCINC,
+                btcnt: BUFFER_LEN as u16,
+                srcaddr: buffer.as_ptr() as u32 + BUFFER_LEN as u32,
+                dstaddr: self.data_address,
+                descaddr: 0,
+            };
+            let dmac = &*DMAC::ptr();
+            dmac.channel[self.channel]
+                .chctrla
+                .modify(|r, w| w.bits(r.bits() | CHCTRLA_ENABLE));
+        }
+    }
+}
+
+// Each colour byte becomes three SPI bytes, most significant bit first
+fn encode_byte(byte: u8, out: &mut [u8]) {
+    let mut pattern: u32 = 0;
+    for bit in (0..8).rev() {
+        pattern = (pattern << 3) | if byte & (1 << bit) != 0 { 0b110 } else { 0b100 };
+    }
+    out.copy_from_slice(&pattern.to_be_bytes()[1..]);
+}
+
+impl SmartLedsWrite for DmaWs2812 {
+    type Error = ();
+    type Color = RGB8;
+
+    // Returns as soon as the transfer has started, only waits if the previous one is still going
+    fn write<T, I>(&mut self, iterator: T) -> Result<(), Self::Error>
+    where
+        T: Iterator<Item = I>,
+        I: Into<Self::Color>,
+    {
+        while self.busy() {}
+
+        let buffer = unsafe { &mut BUFFERS[self.channel] };
+        for (led, color) in buffer[..STRIP_LENGTH * BYTES_PER_LED]
+            .chunks_exact_mut(BYTES_PER_LED)
+            .zip(iterator)
+        {
+            let color = color.into();
+            // the LEDs want green first
+            encode_byte(color.g, &mut led[0..3]);
+            encode_byte(color.r, &mut led[3..6]);
+            encode_byte(color.b, &mut led[6..9]);
+        }
+
+        self.start();
+        Ok(())
+    }
+}
@@ -0,0 +1,121 @@
+use bsp::hal::pac::NVMCTRL;
+use firmware::storage::{Slot, Storage, StorageError, SLOT_SIZE};
+use itsybitsy_m4 as bsp;
+
+// The slots live in the last blocks of the 512KB flash, far away from the program
+const FLASH_END: u32 = 0x0008_0000;
+const BLOCK_SIZE: u32 = 8192;
+const PAGE_SIZE: u32 = 512;
+const SLOT_MAGIC: u32 = 0x534c_4f54;
+const HEADER_SIZE: usize = 8;
+
+// Every slot is one erase block: a magic word, the blob length and then the blob itself
+pub struct FlashStorage {
+    nvmctrl: NVMCTRL,
+}
+
+impl FlashStorage {
+    pub fn new(nvmctrl: NVMCTRL) -> Self {
+        // writes go to the page buffer and are only committed by an explicit write page command
+        nvmctrl.ctrla.modify(|_, w| w.wmode().man());
+        FlashStorage { nvmctrl }
+    }
+
+    fn slot_address(slot: Slot) -> u32 {
+        let block = match slot {
+            Slot::IdleScene => 1,
+            Slot::Presets => 2,
+            Slot::Palettes => 3,
+        };
+        FLASH_END - block * BLOCK_SIZE
+    }
+
+    fn wait_ready(&self) {
+        while self.nvmctrl.status.read().ready().bit_is_clear() {}
+    }
+
+    fn erase_block(&mut self, address: u32) {
+        self.wait_ready();
+        self.nvmctrl.addr.write(|w| unsafe { w.addr().bits(address) });
+        self.nvmctrl.ctrlb.write(|w| w.cmdex().key().cmd().eb());
+        self.wait_ready();
+    }
+
+    fn write_page(&mut self, address: u32, words: &[u32]) {
+        self.wait_ready();
+        self.nvmctrl.ctrlb.write(|w| w.cmdex().key().cmd().pbc());
+        self.wait_ready();
+
+        // filling the page buffer is done by writing to the flash addresses themselves
+        let page = address as *mut u32;
+        for (i, word) in words.iter().enumerate() {
+            unsafe { core::ptr::write_volatile(page.add(i), *word) };
+        }
+
+        self.nvmctrl.addr.write(|w| unsafe { w.addr().bits(address) });
+        self.nvmctrl.ctrlb.write(|w| w.cmdex().key().cmd().wp());
+        self.wait_ready();
+    }
+}
+
+impl Storage for FlashStorage {
+    fn load(&self, slot: Slot) -> Option<&[u8]> {
+        let address = FlashStorage::slot_address(slot) as *const u32;
+        // flash is memory mapped, so the blob can be handed out without copying it
+        unsafe {
+            if core::ptr::read_volatile(address) != SLOT_MAGIC {
+                return None;
+            }
+            let len = core::ptr::read_volatile(address.add(1)) as usize;
+            if len > SLOT_SIZE {
+                return None;
+            }
+            Some(core::slice::from_raw_parts(
+                (address as *const u8).add(HEADER_SIZE),
+                len,
+            ))
+        }
+    }
+
+    fn store(&mut self, slot: Slot, data: &[u8]) -> Result<(), StorageError> {
+        if data.len() > SLOT_SIZE {
+            return Err(StorageError::TooLarge);
+        }
+
+        let address = FlashStorage::slot_address(slot);
+        cortex_m::interrupt::free(|_| {
+            self.erase_block(address);
+
+            // assemble each page in RAM, the page buffer only takes whole 32 bit words
+            let total_len = HEADER_SIZE + data.len();
+            let mut offset = 0;
+            while offset < total_len {
+                let mut words = [0xffff_ffffu32; (PAGE_SIZE / 4) as usize];
+                for (i, word) in words.iter_mut().enumerate() {
+                    let mut bytes = [0xffu8; 4];
+                    for (j, byte) in bytes.iter_mut().enumerate() {
+                        let idx = offset + i * 4 + j;
+                        if idx < HEADER_SIZE {
+                            let header = [SLOT_MAGIC.to_le_bytes(), (data.len() as u32).to_le_bytes()];
+                            *byte = header[idx / 4][idx % 4];
+                        } else if idx < total_len {
+                            *byte = data[idx - HEADER_SIZE];
+                        }
+                    }
+                    *word = u32::from_le_bytes(bytes);
+                }
+                self.write_page(address + offset as u32, &words);
+                offset += PAGE_SIZE as usize;
+            }
+        });
+
+        let errors = self.nvmctrl.intflag.read();
+        if errors.addre().bit_is_set() || errors.proge().bit_is_set() || errors.locke().bit_is_set() {
+            self.nvmctrl
+                .intflag
+                .write(|w| w.addre().set_bit().proge().set_bit().locke().set_bit());
+            return Err(StorageError::Flash);
+        }
+        Ok(())
+    }
+}
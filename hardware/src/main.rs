@@ -1,16 +1,37 @@
 #![no_std]
 #![no_main]
 
+#[cfg(feature = "dma-output")]
+mod dma_ws2812;
+mod flash;
+mod output;
+
+use core::mem::MaybeUninit;
+use core::panic::PanicInfo;
+
 use arrform::{arrform, ArrForm};
 use bsp::entry;
 use bsp::hal::timer::TimerCounter;
+use bsp::hal::watchdog::{Watchdog, WatchdogTimeout};
 use bsp::hal::{self, rtc, usb::UsbBus};
 use cortex_m::interrupt::free as disable_interrupts;
-use cortex_m::peripheral::NVIC;
-use firmware::json_events::add_events_from_json;
-use firmware::new_strips::{calculate_new_strips, MAX_EVENTS, SERIAL_NUM, CLOCK_MULTIPLIER};
-use firmware::starting_events::add_starting_events;
+use cortex_m::peripheral::{NVIC, SCB};
+use firmware::clock::VirtualClock;
+use firmware::crash::{CrashRecord, CrashReport, STABLE_SECONDS};
+use firmware::governor::FrameGovernor;
+use firmware::idle_scene::IdleScene;
+use firmware::json_events::{add_events_from_json, CommandContext, MAX_LINE_LEN};
+use firmware::new_strips::{MAX_EVENTS, SERIAL_NUM, CLOCK_MULTIPLIER};
+use firmware::palettes::Palettes;
+use firmware::pipeline::FramePipeline;
+use firmware::storage::SlotBuffer;
+use firmware::stream::{StreamLayer, STREAM_SYNC};
 use firmware::structs::EventWrapper;
+use firmware::telemetry::Telemetry;
+use flash::FlashStorage;
+use output::Neopixels;
+#[cfg(feature = "dma-output")]
+use hal::clock::{ClockGenId, ClockSource};
 use hal::clock::GenericClockController;
 use hal::pac::interrupt;
 use hal::pac::{CorePeripherals, Peripherals};
@@ -18,10 +39,9 @@ use hal::prelude::*;
 use heapless::Vec;
 use micromath::F32Ext;
 use itsybitsy_m4 as bsp;
-use panic_halt as _;
-use smart_leds_trait::SmartLedsWrite;
 use usb_device::{bus::UsbBusAllocator, prelude::*};
 use usbd_serial::{SerialPort, USB_CLASS_CDC};
+#[cfg(not(feature = "dma-output"))]
 use ws2812_timer_delay::Ws2812;
 
 #[entry]
@@ -35,9 +55,24 @@ fn main() -> ! {
         &mut peripherals.OSCCTRL,
         &mut peripherals.NVMCTRL,
     );
+    let mut storage = FlashStorage::new(peripherals.NVMCTRL);
     let pins = bsp::Pins::new(peripherals.PORT);
     let mut debug_led = pins.d13.into_push_pull_output();
 
+    // Find out whether the last run ended in a crash before anything can overwrite the record
+    let reset_cause = peripherals.RSTC.rcause.read();
+    let last_crash = unsafe {
+        CrashReport::from_reset(
+            &mut *CRASH_RECORD.as_mut_ptr(),
+            reset_cause.wdt().bit_is_set(),
+            reset_cause.por().bit_is_set(),
+        )
+    };
+
+    // Resets the board if the main loop stops feeding it for ~4 seconds
+    let mut watchdog = Watchdog::new(peripherals.WDT);
+    watchdog.start(WatchdogTimeout::Cycles4K as u8);
+
     // Need to make it first a clock, then into a count32 mode in order for the counter to start
     // WHY??? I don't know
     let count_timer = rtc::Rtc::clock_mode(peripherals.RTC, 1024.hz(), &mut peripherals.MCLK);
@@ -45,18 +80,54 @@ fn main() -> ! {
 
     // Neopixel setup
     let gclk0 = clocks.gclk0();
+    #[cfg(not(feature = "dma-output"))]
     let timer_clock = clocks.tc2_tc3(&gclk0).unwrap();
+    #[cfg(not(feature = "dma-output"))]
     let mut timers = (
         TimerCounter::tc2_(&timer_clock, peripherals.TC2, &mut peripherals.MCLK),
         TimerCounter::tc3_(&timer_clock, peripherals.TC3, &mut peripherals.MCLK),
     );
-    // DOCS say that this should be 3MHz, but it seems to work also with 6MHz, maybe better
-    timers.0.start(6.mhz());
-    timers.1.start(6.mhz());
-    let mut neopixels = (
-        Ws2812::new(timers.0, pins.d2.into_push_pull_output()),
-        Ws2812::new(timers.1, pins.d3.into_push_pull_output()),
-    );
+    #[cfg(not(feature = "dma-output"))]
+    let mut neopixels = {
+        // DOCS say that this should be 3MHz, but it seems to work also with 6MHz, maybe better
+        timers.0.start(6.mhz());
+        timers.1.start(6.mhz());
+        Neopixels(
+            Ws2812::new(timers.0, pins.d2.into_push_pull_output()),
+            Ws2812::new(timers.1, pins.d3.into_push_pull_output()),
+        )
+    };
+    // The SERCOMs shift the strips out by DMA, see dma_ws2812 for the pins this needs
+    #[cfg(feature = "dma-output")]
+    let mut neopixels = {
+        // The SERCOM cores can't run from the 120MHz gclk0, the 48MHz DFLL is within their limit
+        // and divides evenly into the 2.4MHz bit rate
+        let sercom_gclk = clocks
+            .configure_gclk_divider_and_source(ClockGenId::GCLK4, 1, ClockSource::DFLL, false)
+            .unwrap();
+        let _sercom_clocks = (
+            clocks.sercom0_core(&sercom_gclk).unwrap(),
+            clocks.sercom1_core(&sercom_gclk).unwrap(),
+        );
+        let _data_pins = (
+            pins.d2.into_alternate::<hal::gpio::D>(),
+            pins.mosi.into_alternate::<hal::gpio::D>(),
+        );
+        let strips = dma_ws2812::strips(
+            peripherals.DMAC,
+            (&peripherals.SERCOM0, &peripherals.SERCOM1),
+            &mut peripherals.MCLK,
+        );
+        Neopixels(strips.0, strips.1)
+    };
+
+    // Paces the main loop at the target frame rate, restarted whenever the host changes the rate
+    let frame_timer_clock = clocks.tc4_tc5(&gclk0).unwrap();
+    let mut frame_timer =
+        TimerCounter::tc4_(&frame_timer_clock, peripherals.TC4, &mut peripherals.MCLK);
+    let mut governor = FrameGovernor::new();
+    let mut timer_fps = governor.target_fps();
+    frame_timer.start(timer_fps.hz());
 
     // USB setup
     let bus_allocator = unsafe {
@@ -100,6 +171,21 @@ fn main() -> ! {
         }
     });
 
+    // Animations run on the virtual clock so they can be paused, slowed down or stepped
+    let mut clock = VirtualClock::new();
+    let mut telemetry = Telemetry::new();
+    telemetry.last_crash = last_crash;
+    let mut marked_stable = false;
+
+    // In safe mode only the built in idle scene is played, in case the uploaded one is what
+    // keeps crashing the board
+    let mut idle_scene = IdleScene::new();
+    idle_scene.safe_mode = last_crash.safe_mode;
+    idle_scene.load(&storage);
+
+    let mut palettes = Palettes::new();
+    palettes.load(&storage);
+
     // Flash the LED every 10 loops
     let mut loop_counter: u32 = 0;
     loop {
@@ -107,24 +193,76 @@ fn main() -> ! {
         if loop_counter % 10 == 0 {
             debug_led.toggle().unwrap();
         }
+        watchdog.feed();
 
-        let timer_seconds: f32 = count_timer.count32() as f32 * CLOCK_MULTIPLIER;
+        // USB keeps being served by its interrupts while we wait for the next frame
+        while frame_timer.wait().is_err() {}
 
-        if loop_counter == 100 {
-            unsafe {
-                add_starting_events(&mut ACTIVE_EVENTS, timer_seconds);
-            }
+        let real_seconds: f32 = count_timer.count32() as f32 * CLOCK_MULTIPLIER;
+        let now = clock.tick(governor.start_frame(real_seconds));
+        telemetry.record_frame(real_seconds);
+
+        if !marked_stable && real_seconds > STABLE_SECONDS {
+            unsafe { (*CRASH_RECORD.as_mut_ptr()).mark_stable() };
+            marked_stable = true;
         }
 
+        // This should be safe as only the main loop uses ACTIVE_EVENTS
+        unsafe { idle_scene.update(now, real_seconds, &mut ACTIVE_EVENTS, &storage) };
+
         // This should be safe, as disable_interrupts stops USB interrupts (only place which uses JSON_BUF)
-        // and only the main loop uses ACTIVE_EVENTS
+        // and only the main loop uses ACTIVE_EVENTS and STREAM
         disable_interrupts(|_| unsafe {
+            telemetry.record_rx_len(JSON_BUF_LEN);
+
             let mut pos = 0;
             while pos < JSON_BUF_LEN {
+                // streamed frames are binary and may contain newlines, so they're taken whole
+                if JSON_BUF[pos] == STREAM_SYNC {
+                    if pos > 0 {
+                        // the line before the packet was cut off, it can never be completed
+                        telemetry.parse_errors += 1;
+                        JSON_BUF.copy_within(pos..JSON_BUF_LEN, 0);
+                        JSON_BUF_LEN -= pos;
+                        pos = 0;
+                    }
+                    match STREAM.take_packet(&JSON_BUF[..JSON_BUF_LEN], real_seconds) {
+                        Some(len) => {
+                            JSON_BUF.copy_within(len..JSON_BUF_LEN, 0);
+                            JSON_BUF_LEN -= len;
+                            continue;
+                        }
+                        // the rest of the packet is still on its way
+                        None => break,
+                    }
+                }
                 if JSON_BUF[pos] == b'\n' {
-                    let json_str = core::str::from_utf8(&JSON_BUF[0..=pos]).unwrap();
-
-                    add_events_from_json(&mut ACTIVE_EVENTS, json_str, timer_seconds);
+                    match core::str::from_utf8(&JSON_BUF[0..=pos]) {
+                        Ok(json_str) => {
+                            let mut context = CommandContext {
+                                clock: &mut clock,
+                                telemetry: &mut telemetry,
+                                crash_record: &mut *CRASH_RECORD.as_mut_ptr(),
+                                idle_scene: &mut idle_scene,
+                                governor: &mut governor,
+                                palettes: &mut palettes,
+                                stream: &mut STREAM,
+                                storage: &mut storage,
+                                slot_buffer: &mut SLOT_BUFFER,
+                            };
+                            let response = add_events_from_json(
+                                &mut ACTIVE_EVENTS,
+                                &mut context,
+                                json_str,
+                                now,
+                                real_seconds,
+                            );
+                            if let Some(response) = response {
+                                write_serial(response.as_bytes());
+                            }
+                        }
+                        Err(_) => telemetry.parse_errors += 1,
+                    }
 
                     JSON_BUF.copy_within(pos + 1..JSON_BUF_LEN, 0);
                     JSON_BUF_LEN -= pos + 1;
@@ -133,13 +271,24 @@ fn main() -> ! {
                     pos += 1;
                 }
             }
+            // a line longer than the buffer can never be completed, and would keep everything
+            // after it out
+            if JSON_BUF_LEN == MAX_JSON_LEN {
+                telemetry.parse_errors += 1;
+                JSON_BUF_LEN = 0;
+            }
         });
-        // This should be safe as only the main loop uses ACTIVE_EVENTS
-        let strips = unsafe { calculate_new_strips(timer_seconds, &mut ACTIVE_EVENTS) };
-        disable_interrupts(|_| {
-            neopixels.0.write(strips.strips.0.iter().cloned()).unwrap();
-            neopixels.1.write(strips.strips.1.iter().cloned()).unwrap();
-        })
+        // This should be safe as only the main loop uses ACTIVE_EVENTS, PIPELINE and STREAM
+        unsafe {
+            PIPELINE.render(now, real_seconds, &mut ACTIVE_EVENTS, &palettes, &mut STREAM);
+            PIPELINE.present(&mut neopixels);
+        }
+        governor.end_frame(count_timer.count32() as f32 * CLOCK_MULTIPLIER);
+
+        if governor.target_fps() != timer_fps {
+            timer_fps = governor.target_fps();
+            frame_timer.start(timer_fps.hz());
+        }
     }
 }
 
@@ -147,13 +296,22 @@ static mut USB_ALLOCATOR: Option<UsbBusAllocator<UsbBus>> = None;
 static mut USB_BUS: Option<UsbDevice<UsbBus>> = None;
 static mut USB_SERIAL: Option<SerialPort<UsbBus>> = None;
 
+// Not zeroed at start up, so it survives the reset done by the panic handler
+#[link_section = ".uninit.CRASH_RECORD"]
+static mut CRASH_RECORD: MaybeUninit<CrashRecord> = MaybeUninit::uninit();
+
 // Only for main thread
 static mut ACTIVE_EVENTS: Vec<EventWrapper, MAX_EVENTS> = Vec::new();
+static mut SLOT_BUFFER: SlotBuffer = Vec::new();
+static mut PIPELINE: FramePipeline = FramePipeline::new();
+static mut STREAM: StreamLayer = StreamLayer::new();
 
 // Shared between main and USB interrupts
-const MAX_JSON_LEN: usize = 4096 * 2;
+const MAX_JSON_LEN: usize = MAX_LINE_LEN;
 static mut JSON_BUF: [u8; MAX_JSON_LEN] = [0; MAX_JSON_LEN];
 static mut JSON_BUF_LEN: usize = 0;
+// gives up on a response when the host isn't reading, instead of hanging the animations
+const MAX_WRITE_ATTEMPTS: u32 = 10_000;
 
 fn poll_usb() {
     disable_interrupts(|_| unsafe {
@@ -167,8 +325,11 @@ fn poll_usb() {
                         if i >= count {
                             break;
                         }
-                        JSON_BUF[JSON_BUF_LEN] = *c;
-                        JSON_BUF_LEN += 1;
+                        // drop what doesn't fit rather than overflowing, the main loop then throws the line away
+                        if JSON_BUF_LEN < MAX_JSON_LEN {
+                            JSON_BUF[JSON_BUF_LEN] = *c;
+                            JSON_BUF_LEN += 1;
+                        }
                     }
                 };
             };
@@ -176,6 +337,35 @@ fn poll_usb() {
     });
 }
 
+// Blocks until all bytes are queued, polling the bus ourselves as the USB interrupts may be disabled
+fn write_serial(bytes: &[u8]) {
+    disable_interrupts(|_| unsafe {
+        if let Some(usb_dev) = USB_BUS.as_mut() {
+            if let Some(serial) = USB_SERIAL.as_mut() {
+                let mut written = 0;
+                let mut attempts = 0;
+                while written < bytes.len() && attempts < MAX_WRITE_ATTEMPTS {
+                    match serial.write(&bytes[written..]) {
+                        Ok(count) => written += count,
+                        Err(_) => {
+                            usb_dev.poll(&mut [serial]);
+                        }
+                    }
+                    attempts += 1;
+                }
+            };
+        };
+    });
+}
+
+// Leave the panic location for the next boot and reset, rather than freezing the strips
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    cortex_m::interrupt::disable();
+    unsafe { (*CRASH_RECORD.as_mut_ptr()).record_panic(info) };
+    SCB::sys_reset();
+}
+
 #[interrupt]
 fn USB_OTHER() {
     poll_usb();
@@ -0,0 +1,44 @@
+#[cfg(feature = "dma-output")]
+use crate::dma_ws2812::DmaWs2812;
+#[cfg(not(feature = "dma-output"))]
+use cortex_m::interrupt::free as disable_interrupts;
+use firmware::new_strips::Strips;
+use firmware::pipeline::FrameSink;
+use smart_leds_trait::SmartLedsWrite;
+#[cfg(not(feature = "dma-output"))]
+use smart_leds_trait::RGB8;
+
+// Both strips of the board, fed by the frame pipeline
+pub struct Neopixels<A, B>(pub A, pub B);
+
+// Bit banging needs exact timing, so USB has to wait until both strips are written
+#[cfg(not(feature = "dma-output"))]
+impl<A, B> FrameSink for Neopixels<A, B>
+where
+    A: SmartLedsWrite<Color = RGB8>,
+    B: SmartLedsWrite<Color = RGB8>,
+{
+    fn show(&mut self, frame: &Strips) {
+        disable_interrupts(|_| {
+            let _ = self.0.write(frame.strips.0.iter().cloned());
+            let _ = self.1.write(frame.strips.1.iter().cloned());
+        });
+    }
+
+    fn ready(&mut self) -> bool {
+        true
+    }
+}
+
+// The frame is encoded into the DMA buffers, which are shifted out while the next one renders
+#[cfg(feature = "dma-output")]
+impl FrameSink for Neopixels<DmaWs2812, DmaWs2812> {
+    fn show(&mut self, frame: &Strips) {
+        let _ = self.0.write(frame.strips.0.iter().cloned());
+        let _ = self.1.write(frame.strips.1.iter().cloned());
+    }
+
+    fn ready(&mut self) -> bool {
+        !self.0.busy() && !self.1.busy()
+    }
+}